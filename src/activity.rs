@@ -0,0 +1,78 @@
+//! Tracks when the server last handled a request, so it can shut itself down
+//! after a period of inactivity.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tokio::time::Instant;
+
+#[derive(Clone)]
+pub struct Activity {
+    started: Instant,
+    // Milliseconds after `started` at which the last request finished.
+    last_ms: Arc<AtomicU64>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_ms: Arc::new(AtomicU64::new(0)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Marks a request as in progress until the returned guard is dropped.
+    pub fn begin(&self) -> ActivityGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        ActivityGuard(self.clone())
+    }
+
+    fn touch(&self) {
+        let now_ms = self.started.elapsed().as_millis() as u64;
+        self.last_ms.fetch_max(now_ms, Ordering::SeqCst);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_ms.load(Ordering::SeqCst));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    /// Resolves once nothing is in flight and nothing has finished for
+    /// `timeout`.
+    pub async fn wait_until_idle(&self, timeout: Duration) {
+        loop {
+            let idle_for = self.idle_for();
+            if self.in_flight.load(Ordering::SeqCst) == 0 && idle_for >= timeout {
+                return;
+            }
+            let remaining = timeout.saturating_sub(idle_for);
+            tokio::time::sleep(remaining.max(Duration::from_secs(1))).await;
+        }
+    }
+}
+
+pub struct ActivityGuard(Activity);
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub async fn track(State(activity): State<Activity>, request: Request, next: Next) -> Response {
+    let _guard = activity.begin();
+    next.run(request).await
+}
@@ -1,162 +1,4425 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context};
+use rusqlite::{named_params, params, params_from_iter, Connection, OpenFlags, OptionalExtension};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    bloom::BloomFilter, filter::Filter, sync::BucketHasher, CacheStats, Diagnostics, Duplicate,
+    Item, KeySize, Leadership, Op, Page, PlanStep, QueuedRequest, SchemaObject, SlowRequest,
+};
+
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    /// Create the "items" table if it doesn't exist. When this is off the
+    /// table must already be there (e.g. created by an external migration
+    /// tool), and `open` fails if it's missing or lacks the expected columns.
+    pub create_if_missing: bool,
+    /// Secondary database files to attach read-only, as `(alias, path)`. Each
+    /// one is expected to have an "items" table of its own, readable with
+    /// [`DatabaseClient::get_attached_item`] and friends.
+    pub attach: Vec<(String, PathBuf)>,
+    /// Create the key column with `COLLATE NOCASE`, so that keys differing
+    /// only in (ASCII) case are the same key for lookups, writes and ordering.
+    ///
+    /// This only takes effect when the table is created: an existing table
+    /// keeps the collation it was created with, and `open` just logs a
+    /// warning on a mismatch. Converting means rebuilding the table (create a
+    /// new one with the collation, copy the rows over, drop the old one and
+    /// rename), and the copy fails if two existing keys differ only in case.
+    pub case_insensitive_keys: bool,
+    /// `PRAGMA synchronous` for the connection, or SQLite's default (FULL)
+    /// when unset. Individual writes can still ask for FULL with
+    /// [`DatabaseClient::put_item_durable`].
+    pub synchronous: Option<Synchronous>,
+    /// `PRAGMA wal_autocheckpoint`: checkpoint the WAL once it grows past this
+    /// many pages, or never if 0, leaving it to whoever manages the file. Only
+    /// matters in WAL mode. SQLite's default (1000) when unset.
+    pub wal_autocheckpoint: Option<u32>,
+    /// Secondary indexes, as `(name, expression)`, e.g.
+    /// `("type", "json_extract(value, '$.type')")`. Each becomes a virtual
+    /// generated column on "items" with an index on it, queried with
+    /// [`DatabaseClient::get_by_index`]. The expression is SQL over the row's
+    /// columns and is used as-is, so it must come from trusted configuration.
+    ///
+    /// Indexes are added when missing but never changed: to change an
+    /// expression, drop the `idx_<name>` column (and its index) first.
+    pub indexes: Vec<(String, String)>,
+    /// Open in SQLite's shared-cache mode, so that connections in this
+    /// process to the same database share one cache, and for an in-memory
+    /// database (`file::memory:`, `file:name?mode=memory`), the database
+    /// itself. A `cache=shared` parameter in a `file:` URI path does the same
+    /// for just that database.
+    ///
+    /// A shared in-memory database lasts only as long as some connection to
+    /// it is open, so reopening one (see [`SpawnOptions::reopen`]) starts
+    /// from empty unless another connection kept it alive.
+    pub shared_cache: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            create_if_missing: true,
+            attach: Vec::new(),
+            case_insensitive_keys: false,
+            synchronous: None,
+            wal_autocheckpoint: None,
+            indexes: Vec::new(),
+            shared_cache: false,
+        }
+    }
+}
+
+/// How hard SQLite works to get a commit onto disk before reporting success.
+/// See <https://www.sqlite.org/pragma.html#pragma_synchronous>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn from_pragma(value: i64) -> Self {
+        match value {
+            0 => Self::Off,
+            1 => Self::Normal,
+            2 => Self::Full,
+            _ => Self::Extra,
+        }
+    }
+
+    fn as_pragma(self) -> &'static str {
+        match self {
+            Self::Off => "OFF",
+            Self::Normal => "NORMAL",
+            Self::Full => "FULL",
+            Self::Extra => "EXTRA",
+        }
+    }
+}
+
+pub fn open(path: PathBuf) -> anyhow::Result<Connection> {
+    open_with_options(path, OpenOptions::default())
+}
+
+/// Opens the database at `path`, which can also be a `file:` URI such as
+/// `file::memory:?cache=shared`.
+pub fn open_with_options(path: PathBuf, options: OpenOptions) -> anyhow::Result<Connection> {
+    let mut flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+        | OpenFlags::SQLITE_OPEN_CREATE
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    if options.shared_cache {
+        flags |= OpenFlags::SQLITE_OPEN_SHARED_CACHE;
+    }
+    let conn = Connection::open_with_flags(path, flags)?;
+    if let Some(synchronous) = options.synchronous {
+        conn.pragma_update(None, "synchronous", synchronous.as_pragma())?;
+    }
+    if let Some(pages) = options.wal_autocheckpoint {
+        conn.pragma_update(None, "wal_autocheckpoint", pages)?;
+    }
+    let collation = if options.case_insensitive_keys {
+        " COLLATE NOCASE"
+    } else {
+        ""
+    };
+    if options.create_if_missing {
+        // Ensure the "items" table exists
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS items \
+                 (key TEXT{collation} PRIMARY KEY, value TEXT NOT NULL)"
+            ),
+            [],
+        )
+        .context("Failed to create table")?;
+        init_item_count(&conn).context("Failed to set up the item count")?;
+    } else {
+        verify_schema(&conn).context("Unexpected schema")?;
+    }
+    // Only "items" is left to whoever manages the schema; the tables below are
+    // ours, and created either way.
+    // Items keyed by arbitrary bytes (e.g. digests) rather than text.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS bin_items (key BLOB PRIMARY KEY, value TEXT NOT NULL)",
+        [],
+    )
+    .context("Failed to create table")?;
+    // Labels on items, see `DatabaseClient::add_tag`. They go when the item
+    // does.
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS tags \
+         (key TEXT{collation} NOT NULL, tag TEXT NOT NULL, PRIMARY KEY (key, tag));
+         CREATE INDEX IF NOT EXISTS tags_by_tag ON tags (tag, key);
+         CREATE TRIGGER IF NOT EXISTS items_tags_delete AFTER DELETE ON items BEGIN
+             DELETE FROM tags WHERE key = old.key;
+         END;"
+    ))
+    .context("Failed to create table")?;
+    // Per-key read counts, see `DatabaseClient::count_read`.
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS read_counts \
+             (key TEXT{collation} PRIMARY KEY, count INTEGER NOT NULL)"
+        ),
+        [],
+    )
+    .context("Failed to create table")?;
+    // Leadership leases, see `DatabaseClient::acquire_leadership`.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS leaders \
+         (key TEXT PRIMARY KEY, leader TEXT NOT NULL, expires_at INTEGER NOT NULL)",
+        [],
+    )
+    .context("Failed to create table")?;
+    if keys_are_case_insensitive(&conn)? != options.case_insensitive_keys {
+        tracing::warn!(
+            requested = options.case_insensitive_keys,
+            "items table was created with a different key collation; using the table's"
+        );
+    }
+    for (name, expression) in &options.indexes {
+        add_index(&conn, name, expression)
+            .with_context(|| format!("Failed to add index {name:?}"))?;
+    }
+    for (alias, path) in &options.attach {
+        attach(&conn, path, alias).with_context(|| format!("Failed to attach {alias:?}"))?;
+    }
+    Ok(conn)
+}
+
+// Keeps a running count of the rows in "items" in bgdb_meta, maintained by
+// triggers so that every way of writing (upserts, pops, range deletes, ...)
+// keeps it right, in the same transaction as the write. It's reconciled with a
+// real count on every open, in case anything bypassed the triggers.
+fn init_item_count(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute_batch(
+        "BEGIN;
+         CREATE TABLE IF NOT EXISTS bgdb_meta (name TEXT PRIMARY KEY, value INTEGER NOT NULL);
+         CREATE TRIGGER IF NOT EXISTS items_count_insert AFTER INSERT ON items BEGIN
+             UPDATE bgdb_meta SET value = value + 1 WHERE name = 'item_count';
+         END;
+         CREATE TRIGGER IF NOT EXISTS items_count_delete AFTER DELETE ON items BEGIN
+             UPDATE bgdb_meta SET value = value - 1 WHERE name = 'item_count';
+         END;
+         INSERT INTO bgdb_meta (name, value) VALUES ('item_count', (SELECT count(*) FROM items))
+             ON CONFLICT(name) DO UPDATE SET value = excluded.value;
+         COMMIT;",
+    )?;
+    Ok(())
+}
+
+fn check_index_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("index name must be non-empty and only contain [A-Za-z0-9_]");
+    }
+    Ok(())
+}
+
+fn has_index_column(conn: &Connection, name: &str) -> anyhow::Result<bool> {
+    let exists = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_xinfo('items') WHERE name = ?1)",
+        [format!("idx_{name}")],
+        |row| row.get(0),
+    )?;
+    Ok(exists)
+}
+
+fn add_index(conn: &Connection, name: &str, expression: &str) -> anyhow::Result<()> {
+    check_index_name(name)?;
+    if !has_index_column(conn, name)? {
+        // Only VIRTUAL generated columns can be added to an existing table;
+        // the index is what makes lookups fast anyway.
+        conn.execute(
+            &format!(
+                "ALTER TABLE items ADD COLUMN idx_{name} GENERATED ALWAYS AS ({expression}) VIRTUAL"
+            ),
+            [],
+        )?;
+    }
+    conn.execute(
+        &format!("CREATE INDEX IF NOT EXISTS items_idx_{name} ON items (idx_{name})"),
+        [],
+    )?;
+    Ok(())
+}
+
+/// Whether `err` (e.g. from [`open_with_options`]) failed because another
+/// connection held a lock on the database, so trying again later may succeed.
+pub fn is_locked(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        matches!(
+            cause
+                .downcast_ref::<rusqlite::Error>()
+                .and_then(rusqlite::Error::sqlite_error_code),
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+        )
+    })
+}
+
+/// Regenerates everything kept alongside "items" from its rows, for a database
+/// last written by an older version, or by something that bypassed the
+/// triggers: reinstalls the item count and its triggers, and rebuilds every
+/// index on the table (including the ones from [`OpenOptions::indexes`]). The
+/// bloom filter needs nothing here, since it's built from the table every time
+/// the database thread starts.
+pub fn rebuild_derived(conn: &Connection) -> anyhow::Result<()> {
+    let started = Instant::now();
+    tracing::info!("rebuilding the item count");
+    conn.execute_batch(
+        "DROP TRIGGER IF EXISTS items_count_insert;
+         DROP TRIGGER IF EXISTS items_count_delete;",
+    )?;
+    init_item_count(conn).context("Failed to set up the item count")?;
+    tracing::info!(elapsed = ?started.elapsed(), "rebuilding indexes");
+    conn.execute_batch("REINDEX items")?;
+    tracing::info!(elapsed = ?started.elapsed(), "rebuilt derived state");
+    Ok(())
+}
+
+/// Attaches the database at `path` read-only, under `alias`.
+pub fn attach(conn: &Connection, path: &Path, alias: &str) -> anyhow::Result<()> {
+    if alias.is_empty() || !alias.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("alias must be non-empty and only contain [A-Za-z0-9_]");
+    }
+    // Connections are opened with SQLITE_OPEN_URI, so ATTACH accepts a URI,
+    // which is the only way to ask for read-only. Escape the characters that
+    // mean something in one.
+    let path = path
+        .to_str()
+        .context("path is not valid UTF-8")?
+        .replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23");
+    conn.execute(
+        "ATTACH DATABASE ?1 AS ?2",
+        params![format!("file:{path}?mode=ro"), alias],
+    )?;
+    Ok(())
+}
+
+// SQLite doesn't report a column's collation through any pragma, so look for
+// it in the table's DDL.
+fn keys_are_case_insensitive(conn: &Connection) -> anyhow::Result<bool> {
+    let sql: String = conn.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'items'",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(sql.to_ascii_uppercase().contains("COLLATE NOCASE"))
+}
+
+fn verify_schema(conn: &Connection) -> anyhow::Result<()> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('items')")?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if columns.is_empty() {
+        bail!("table \"items\" does not exist");
+    }
+    for expected in ["key", "value"] {
+        if !columns.iter().any(|column| column == expected) {
+            bail!("table \"items\" has no {expected:?} column");
+        }
+    }
+    Ok(())
+}
+
+// Upper bound on how many requests get coalesced together. For reads this also
+// keeps the number of bound keys well under SQLite's limit on host parameters.
+const MAX_COALESCED_REQUESTS: usize = 512;
+
+// Requests that keep the database thread busy for longer than this are
+// remembered (the last few of them) for `diagnostics`.
+const SLOW_REQUEST: Duration = Duration::from_millis(100);
+const SLOW_REQUEST_LOG_LEN: usize = 32;
+
+/// The most items [`DatabaseClient::get_all_sorted_by_value`] returns.
+pub const MAX_SORTED_BY_VALUE: u32 = 1000;
+
+// How many distinct keys' read counts are buffered before they're written.
+const READ_COUNT_BATCH: usize = 1024;
+// How long a read count can sit in the buffer before it's written anyway.
+const READ_COUNT_MAX_AGE: Duration = Duration::from_secs(5);
+
+const BLOOM_MIN_CAPACITY: usize = 1024;
+const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+#[derive(Clone, Debug, Default)]
+pub struct SpawnOptions {
+    /// When set, `GetItem` requests that arrive within this window of the
+    /// first one are answered together with a single query. Trades a little
+    /// latency on lone reads for far fewer lookups under bursty, overlapping
+    /// reads.
+    pub read_coalesce_window: Option<Duration>,
+    /// When set, `PutItem` requests that arrive within this window of the
+    /// first one are committed together in one transaction ("group commit"),
+    /// so a burst of small writes pays for one fsync instead of one each.
+    /// Every write still succeeds or fails on its own, and nobody hears back
+    /// until the shared commit is done.
+    pub write_coalesce_window: Option<Duration>,
+    /// Keep an in-memory bloom filter of every key, built by scanning the
+    /// table at startup, so that point reads of keys that were never written
+    /// skip the database entirely. Costs about 10 bits per key.
+    pub bloom_filter: bool,
+    /// The most rows any single listing (`get_all_items`, a page, ...) may
+    /// return. Listings are cut off in SQL, so going over never materializes
+    /// more than one extra row.
+    pub max_result_rows: Option<usize>,
+    /// What to do with a listing (or requested page size) over
+    /// `max_result_rows`: quietly cut it down to the limit when true, or fail
+    /// with [`ResultTooLarge`] when false.
+    pub clamp_result_rows: bool,
+    /// When set, the database thread closes and reopens its connection after
+    /// a run of errors that point at the connection rather than the request
+    /// (I/O errors, corruption, ...). Without it, such a connection just keeps
+    /// failing.
+    pub reopen: Option<Reopen>,
+    /// Name for the database thread, as shown by `top -H`, profilers and
+    /// panic messages. Defaults to "bgdb-worker". Only applies to [`spawn`];
+    /// the loop borrows a pool thread under [`spawn_on_current_runtime`].
+    pub thread_name: Option<String>,
+    /// A nice value for the database thread (Linux only, ignored elsewhere),
+    /// e.g. 10 to keep heavy database work from starving the threads serving
+    /// HTTP. Raising priority (negative values) needs privileges. Only applies
+    /// to [`spawn`].
+    pub thread_nice: Option<i32>,
+    /// Run every request that only reads with `PRAGMA query_only` on, so a
+    /// read path that tries to write by mistake fails instead. The pragma is
+    /// only switched when going from reads to writes or back.
+    pub query_only_reads: bool,
+    /// The longest value, in bytes, that any write may store.
+    pub max_value_bytes: Option<usize>,
+    /// What to do with a write of a value over `max_value_bytes`: store as
+    /// much of it as fits (cut at a character boundary) when true, or fail
+    /// with [`ValueTooLarge`] when false. Either way it's decided before the
+    /// request reaches the database thread, for every kind of write alike.
+    pub truncate_values: bool,
+}
+
+// `SpawnOptions::max_value_bytes` and what to do about it.
+#[derive(Clone, Copy)]
+struct ValueLimit {
+    max: usize,
+    truncate: bool,
+}
+
+impl ValueLimit {
+    fn from_options(options: &SpawnOptions) -> Option<Self> {
+        options.max_value_bytes.map(|max| Self {
+            max,
+            truncate: options.truncate_values,
+        })
+    }
+}
+
+/// Cuts `value` down to at most `max` bytes, without splitting a character.
+/// Returns whether anything was cut.
+pub fn truncate_value(value: &mut String, max: usize) -> bool {
+    if value.len() <= max {
+        return false;
+    }
+    let mut end = max;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+    true
+}
+
+/// A rollup over values read as numbers, see [`DatabaseClient::aggregate`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregate {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl Aggregate {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Self::Sum => "sum(CAST(value AS REAL))",
+            Self::Avg => "avg(CAST(value AS REAL))",
+            Self::Min => "min(CAST(value AS REAL))",
+            Self::Max => "max(CAST(value AS REAL))",
+            Self::Count => "count(*)",
+        }
+    }
+}
+
+/// Which way a window runs from its anchor key, see
+/// [`DatabaseClient::get_window`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The anchor key and the keys after it.
+    Forward,
+    /// The keys before the anchor key.
+    Backward,
+}
+
+/// Which bytes of a value to read, see [`DatabaseClient::get_value_range`].
+/// Offsets are inclusive, as in an HTTP `Range` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `start..=end`, cut short at the end of the value.
+    FromTo(u64, u64),
+    /// From `start` to the end of the value.
+    From(u64),
+    /// The last `n` bytes of the value (or all of it, if it's shorter).
+    Last(u64),
+}
+
+impl ByteRange {
+    // The inclusive offsets this covers in a value of `total` bytes.
+    fn resolve(self, total: u64) -> Option<(u64, u64)> {
+        let (start, end) = match self {
+            Self::FromTo(start, end) => (start, end.min(total.saturating_sub(1))),
+            Self::From(start) => (start, total.saturating_sub(1)),
+            Self::Last(0) => return None,
+            Self::Last(n) => (total.saturating_sub(n), total.saturating_sub(1)),
+        };
+        (start < total && start <= end).then_some((start, end))
+    }
+}
+
+/// Part of a value, see [`DatabaseClient::get_value_range`].
+#[derive(Clone, Debug)]
+pub struct ValueSlice {
+    pub bytes: Vec<u8>,
+    /// Where `bytes` starts and ends (inclusive) within the value.
+    pub start: u64,
+    pub end: u64,
+    /// The length of the whole value.
+    pub total: u64,
+}
+
+/// A query to show SQLite's plan for, see [`DatabaseClient::explain`].
+#[derive(Clone, Debug)]
+pub enum Explain {
+    /// Listing every item, as [`DatabaseClient::get_all_items`] does.
+    Scan,
+    /// A [`DatabaseClient::query`] with this filter, e.g.
+    /// [`Filter::PrefixEq`] for a prefix scan.
+    Query(Filter),
+    /// Deleting the keys in `[start, end)`, as
+    /// [`DatabaseClient::delete_range`] does. Nothing is deleted.
+    Range { start: String, end: String },
+}
+
+/// How the database thread recovers a connection that looks broken.
+#[derive(Clone, Debug)]
+pub struct Reopen {
+    /// What to pass to [`open_with_options`]; normally the same as was used
+    /// to open the connection in the first place.
+    pub path: PathBuf,
+    pub options: OpenOptions,
+    /// How many fatal errors in a row it takes to reopen.
+    pub after_errors: u32,
+    /// How many reopens in a row, without a successful request in between,
+    /// before giving up. The thread then exits, and every request after that
+    /// fails.
+    pub max_reopens: u32,
+}
+
+pub fn spawn(conn: Connection) -> DatabaseClient {
+    spawn_with_options(conn, SpawnOptions::default())
+}
+
+pub fn spawn_with_options(conn: Connection, options: SpawnOptions) -> DatabaseClient {
+    let (db_tx, db_rx) = mpsc::channel::<DbRequest>(32);
+    let queued = Arc::new(QueuedRequests::default());
+    let db_rx = Requests {
+        rx: db_rx,
+        queued: queued.clone(),
+    };
+    let value_limit = ValueLimit::from_options(&options);
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let name = options
+        .thread_name
+        .clone()
+        .unwrap_or_else(|| "bgdb-worker".to_owned());
+    std::thread::Builder::new()
+        .name(name)
+        .spawn({
+            let shutting_down = shutting_down.clone();
+            move || {
+                if let Some(nice) = options.thread_nice {
+                    set_thread_nice(nice);
+                }
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(database_thread(conn, db_rx, options, shutting_down))
+            }
+        })
+        .expect("failed to spawn the database thread");
+    DatabaseClient::new(db_tx, queued, shutting_down, value_limit)
+}
+
+// On Linux, PRIO_PROCESS with who = 0 changes the nice value of just the
+// calling thread, not the whole process.
+#[cfg(target_os = "linux")]
+fn set_thread_nice(nice: i32) {
+    // SAFETY: setpriority has no memory-safety preconditions.
+    let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        tracing::warn!(nice, %err, "failed to set the database thread's priority");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_thread_nice(nice: i32) {
+    tracing::warn!(
+        nice,
+        "setting the database thread's priority is only supported on Linux"
+    );
+}
+
+/// An alternative to [`spawn`] that runs the database loop as a
+/// `spawn_blocking` task on the current tokio runtime rather than on a
+/// dedicated OS thread with a runtime of its own. Panics if called outside of
+/// a runtime.
+///
+/// This avoids building a second runtime, and the thread that hosts the loop
+/// comes from (and eventually returns to) the blocking pool. The catch is that
+/// the loop holds on to that pool thread for as long as the database is open,
+/// so it counts against the runtime's `max_blocking_threads` and competes with
+/// any other blocking work. Dropping the runtime also waits for the loop to
+/// exit, so make sure to call [`DatabaseClient::shutdown`] first.
+pub fn spawn_on_current_runtime(conn: Connection) -> DatabaseClient {
+    spawn_on_current_runtime_with_options(conn, SpawnOptions::default())
+}
+
+pub fn spawn_on_current_runtime_with_options(
+    conn: Connection,
+    options: SpawnOptions,
+) -> DatabaseClient {
+    let (db_tx, db_rx) = mpsc::channel::<DbRequest>(32);
+    let queued = Arc::new(QueuedRequests::default());
+    let db_rx = Requests {
+        rx: db_rx,
+        queued: queued.clone(),
+    };
+    let value_limit = ValueLimit::from_options(&options);
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking({
+        let shutting_down = shutting_down.clone();
+        move || handle.block_on(database_thread(conn, db_rx, options, shutting_down))
+    });
+    DatabaseClient::new(db_tx, queued, shutting_down, value_limit)
+}
+
+/// A write failed because SQLite couldn't get the bytes onto disk, either
+/// because the volume is full or because of a lower-level I/O error. Reads
+/// don't need new space and should keep working.
+#[derive(Debug)]
+pub struct StorageFull;
+
+impl std::fmt::Display for StorageFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("database storage is full or unwritable")
+    }
+}
+
+impl std::error::Error for StorageFull {}
+
+/// A write would have stored a value over `max_value_bytes`.
+#[derive(Debug)]
+pub struct ValueTooLarge {
+    pub len: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for ValueTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "value of {} bytes exceeds the limit of {} bytes",
+            self.len, self.limit
+        )
+    }
+}
+
+impl std::error::Error for ValueTooLarge {}
+
+/// A listing would have returned more than `max_result_rows` rows.
+#[derive(Debug)]
+pub struct ResultTooLarge {
+    pub limit: usize,
+}
+
+impl std::fmt::Display for ResultTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "result would exceed the limit of {} rows", self.limit)
+    }
+}
+
+impl std::error::Error for ResultTooLarge {}
+
+/// A byte range starts past the end of the value it was asked of.
+#[derive(Debug)]
+pub struct RangeNotSatisfiable {
+    /// The length of the value, in bytes.
+    pub total: u64,
+}
+
+impl std::fmt::Display for RangeNotSatisfiable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "range not satisfiable for a value of {} bytes",
+            self.total
+        )
+    }
+}
+
+impl std::error::Error for RangeNotSatisfiable {}
+
+/// A read named an attached database that doesn't exist.
+#[derive(Debug)]
+pub struct UnknownDatabase {
+    pub alias: String,
+}
+
+impl std::fmt::Display for UnknownDatabase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no attached database named {:?}", self.alias)
+    }
+}
+
+impl std::error::Error for UnknownDatabase {}
+
+/// There's no index by that name, see [`OpenOptions::indexes`].
+#[derive(Debug)]
+pub struct UnknownIndex {
+    pub name: String,
+}
+
+impl std::fmt::Display for UnknownIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no index named {:?}", self.name)
+    }
+}
+
+impl std::error::Error for UnknownIndex {}
+
+/// A `CheckEquals` in an [`Op`] batch didn't hold, so none of the batch was
+/// applied.
+#[derive(Debug)]
+pub struct CheckFailed {
+    /// Position of the failed check in the batch.
+    pub index: usize,
+    pub key: String,
+}
+
+impl std::fmt::Display for CheckFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "check on key {:?} (op {}) failed, nothing was applied",
+            self.key, self.index
+        )
+    }
+}
+
+impl std::error::Error for CheckFailed {}
+
+/// A JSON projection was requested, but the stored value isn't JSON.
+#[derive(Debug)]
+pub struct NotJson {
+    pub key: String,
+}
+
+impl std::fmt::Display for NotJson {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "value for key {:?} is not valid JSON", self.key)
+    }
+}
+
+impl std::error::Error for NotJson {}
+
+/// A value that was expected to be an integer, written as decimal text, isn't.
+#[derive(Debug)]
+pub struct NotAnInteger {
+    pub key: String,
+}
+
+impl std::fmt::Display for NotAnInteger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "value for key {:?} is not an integer", self.key)
+    }
+}
+
+impl std::error::Error for NotAnInteger {}
+
+/// The database is shutting down (or has shut down), so it's no longer taking
+/// requests.
+#[derive(Debug)]
+pub struct ShuttingDown;
+
+impl std::fmt::Display for ShuttingDown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "database is shutting down")
+    }
+}
+
+impl std::error::Error for ShuttingDown {}
+
+#[derive(Clone)]
+pub struct DatabaseClient {
+    db_tx: mpsc::Sender<DbRequest>,
+    queued: Arc<QueuedRequests>,
+    // Set by `shutdown`, and by the database thread when it exits for any
+    // reason, so that requests fail with ShuttingDown rather than with
+    // whatever the channel has to say about it.
+    shutting_down: Arc<AtomicBool>,
+    cache: Arc<CacheCounters>,
+    in_flight_gets: Arc<InFlightGets>,
+    // Bumped after sending anything that can change "items". A get only
+    // shares a lookup that started in the current epoch, i.e. one that was
+    // sent after any write its caller has already seen complete.
+    epoch: Arc<AtomicU64>,
+    totals: Arc<Totals>,
+    value_limit: Option<ValueLimit>,
+}
+
+// What's waiting in the request channel, oldest first, as described by its
+// Debug impl (keys and value lengths, never values) and when it was sent.
+// Kept outside the database thread, so it can be read even when the thread is
+// stuck.
+type QueuedRequests = Mutex<VecDeque<(String, Instant)>>;
+
+// The database thread's end of the request channel, which takes requests off
+// `QueuedRequests` as it receives them.
+struct Requests {
+    rx: mpsc::Receiver<DbRequest>,
+    queued: Arc<QueuedRequests>,
+}
+
+impl Requests {
+    // Along with the request, its description from the queue, so the thread
+    // doesn't have to describe every request again just in case it's slow.
+    async fn recv(&mut self) -> Option<(DbRequest, String)> {
+        let request = self.rx.recv().await?;
+        let queued = self
+            .queued
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop_front();
+        // `enqueue` notes every request before sending it, in order, so the
+        // front entry is always this request's.
+        let description = match queued {
+            Some((description, _)) => description,
+            None => format!("{request:?}"),
+        };
+        Some((request, description))
+    }
+}
+
+// Kept for the summary `shutdown` logs.
+struct Totals {
+    started: Instant,
+    // Requests sent to the database thread.
+    requests: AtomicU64,
+    // Items put by requests that succeeded, counting a rewrite of the same
+    // key each time.
+    items_written: AtomicU64,
+}
+
+// For each key being looked up, the `epoch` the lookup started in and the
+// callers waiting to share its answer.
+type InFlightGets = Mutex<HashMap<String, (u64, Vec<GetWaiter>)>>;
+type GetWaiter = oneshot::Sender<anyhow::Result<(Option<Item>, bool)>>;
+
+// Takes a lookup out of `InFlightGets` once it's answered, or when its caller
+// gives up on it, in which case dropping the waiters' senders tells them so.
+struct InFlightGet<'a> {
+    in_flight: &'a InFlightGets,
+    key: Option<String>,
+}
+
+impl InFlightGet<'_> {
+    fn remove(&mut self) -> Vec<GetWaiter> {
+        let Some(key) = self.key.take() else {
+            return Vec::new();
+        };
+        self.in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&key)
+            .map(|(_, waiters)| waiters)
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for InFlightGet<'_> {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+#[derive(Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+enum DbRequest {
+    GetAll {
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Item>>>,
+    },
+    // Alongside the items, how many rows were skipped.
+    GetAllLossy {
+        respond_to: oneshot::Sender<anyhow::Result<(Vec<Item>, u64)>>,
+    },
+    // Alongside the item, whether it was answered without touching the
+    // database.
+    GetItem {
+        key: String,
+        respond_to: oneshot::Sender<anyhow::Result<(Option<Item>, bool)>>,
+    },
+    GetItems {
+        keys: Vec<String>,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Option<Item>>>>,
+    },
+    Exists {
+        key: String,
+        respond_to: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    CountRead {
+        key: String,
+        respond_to: oneshot::Sender<anyhow::Result<()>>,
+    },
+    GetReadCount {
+        key: String,
+        respond_to: oneshot::Sender<anyhow::Result<u64>>,
+    },
+    GetPage {
+        page: u32,
+        page_size: u32,
+        with_total: bool,
+        respond_to: oneshot::Sender<anyhow::Result<Page>>,
+    },
+    Count {
+        estimate: bool,
+        respond_to: oneshot::Sender<anyhow::Result<u64>>,
+    },
+    GetByIndex {
+        name: String,
+        value: serde_json::Value,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Item>>>,
+    },
+    GetByTag {
+        tag: String,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Item>>>,
+    },
+    GetTags {
+        key: String,
+        respond_to: oneshot::Sender<anyhow::Result<Option<Vec<String>>>>,
+    },
+    AddTag {
+        key: String,
+        tag: String,
+        respond_to: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    RemoveTag {
+        key: String,
+        tag: String,
+        respond_to: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    Query {
+        filter: Filter,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Item>>>,
+    },
+    Aggregate {
+        prefix: String,
+        op: Aggregate,
+        respond_to: oneshot::Sender<anyhow::Result<Option<f64>>>,
+    },
+    GetKeySizes {
+        with_size: bool,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<KeySize>>>,
+    },
+    Diff {
+        prefix_len: usize,
+        client_buckets: HashMap<String, u64>,
+        respond_to: oneshot::Sender<anyhow::Result<BTreeMap<String, Option<u64>>>>,
+    },
+    TopBySize {
+        n: u32,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<KeySize>>>,
+    },
+    ListPrefixes {
+        prefix: String,
+        delimiter: String,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<String>>>,
+    },
+    FindDuplicates {
+        min_count: u32,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Duplicate>>>,
+    },
+    GetAllSortedByValue {
+        descending: bool,
+        limit: u32,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Item>>>,
+    },
+    GetWindow {
+        start_key: String,
+        count: u32,
+        direction: Direction,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Item>>>,
+    },
+    ForEach {
+        // The Mutex is only there to make the request Sync (so a failed send
+        // converts into an anyhow::Error); the callback only ever runs on the
+        // database thread.
+        f: Mutex<Box<dyn FnMut(Item) + Send>>,
+        respond_to: oneshot::Sender<anyhow::Result<usize>>,
+    },
+    GetAllAttached {
+        alias: String,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Item>>>,
+    },
+    GetAttachedItem {
+        alias: String,
+        key: String,
+        respond_to: oneshot::Sender<anyhow::Result<Option<Item>>>,
+    },
+    GetAllProjected {
+        path: String,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Item>>>,
+    },
+    GetItemProjected {
+        key: String,
+        path: String,
+        respond_to: oneshot::Sender<anyhow::Result<Option<Item>>>,
+    },
+    GetValueRange {
+        key: String,
+        range: ByteRange,
+        respond_to: oneshot::Sender<anyhow::Result<Option<ValueSlice>>>,
+    },
+    GetItemBinKey {
+        key: Vec<u8>,
+        respond_to: oneshot::Sender<anyhow::Result<Option<String>>>,
+    },
+    PutItem {
+        item: Item,
+        respond_to: oneshot::Sender<anyhow::Result<()>>,
+    },
+    PutItemBinKey {
+        key: Vec<u8>,
+        value: String,
+        respond_to: oneshot::Sender<anyhow::Result<()>>,
+    },
+    PutItemDurable {
+        item: Item,
+        respond_to: oneshot::Sender<anyhow::Result<()>>,
+    },
+    PutIfChanged {
+        item: Item,
+        respond_to: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    PutItemReturning {
+        item: Item,
+        durable: bool,
+        if_changed: bool,
+        respond_to: oneshot::Sender<anyhow::Result<(Item, bool)>>,
+    },
+    PutItems {
+        items: Vec<Item>,
+        respond_to: oneshot::Sender<anyhow::Result<()>>,
+    },
+    PutItemsIfAbsent {
+        items: Vec<Item>,
+        respond_to: oneshot::Sender<anyhow::Result<usize>>,
+    },
+    Apply {
+        ops: Vec<Op>,
+        respond_to: oneshot::Sender<anyhow::Result<()>>,
+    },
+    PutItemsBestEffort {
+        items: Vec<Item>,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<Result<(), String>>>>,
+    },
+    Pop {
+        key: String,
+        respond_to: oneshot::Sender<anyhow::Result<Option<Item>>>,
+    },
+    // Replies with the item under its new key.
+    Archive {
+        from: String,
+        to_prefix: String,
+        respond_to: oneshot::Sender<anyhow::Result<Option<Item>>>,
+    },
+    DeleteRange {
+        start: String,
+        end: String,
+        respond_to: oneshot::Sender<anyhow::Result<usize>>,
+    },
+    PopAny {
+        respond_to: oneshot::Sender<anyhow::Result<Option<Item>>>,
+    },
+    AcquireLeadership {
+        key: String,
+        candidate_id: String,
+        ttl: Duration,
+        respond_to: oneshot::Sender<anyhow::Result<Leadership>>,
+    },
+    RenewLeadership {
+        key: String,
+        candidate_id: String,
+        ttl: Duration,
+        respond_to: oneshot::Sender<anyhow::Result<Leadership>>,
+    },
+    // Replies with the new value and whether the floor stopped it.
+    DecrementFloor {
+        key: String,
+        delta: i64,
+        floor: i64,
+        respond_to: oneshot::Sender<anyhow::Result<(i64, bool)>>,
+    },
+    Flush {
+        respond_to: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Diagnostics {
+        respond_to: oneshot::Sender<anyhow::Result<Diagnostics>>,
+    },
+    GetSchema {
+        respond_to: oneshot::Sender<anyhow::Result<Vec<SchemaObject>>>,
+    },
+    Explain {
+        query: Explain,
+        respond_to: oneshot::Sender<anyhow::Result<Vec<PlanStep>>>,
+    },
+    Warmup {
+        full: bool,
+        respond_to: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Maintain {
+        incremental_vacuum: bool,
+        respond_to: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Shutdown {
+        respond_to: oneshot::Sender<anyhow::Result<()>>,
+    },
+}
+impl DbRequest {
+    // Requests that never write to the database (see
+    // `SpawnOptions::query_only_reads`).
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Self::GetAll { .. }
+                | Self::GetAllLossy { .. }
+                | Self::GetItem { .. }
+                | Self::GetItems { .. }
+                | Self::Exists { .. }
+                | Self::GetPage { .. }
+                | Self::Count { .. }
+                | Self::GetByIndex { .. }
+                | Self::GetByTag { .. }
+                | Self::GetTags { .. }
+                | Self::Query { .. }
+                | Self::Aggregate { .. }
+                | Self::GetKeySizes { .. }
+                | Self::Diff { .. }
+                | Self::TopBySize { .. }
+                | Self::ListPrefixes { .. }
+                | Self::FindDuplicates { .. }
+                | Self::GetAllSortedByValue { .. }
+                | Self::GetWindow { .. }
+                | Self::ForEach { .. }
+                | Self::GetAllAttached { .. }
+                | Self::GetAttachedItem { .. }
+                | Self::GetAllProjected { .. }
+                | Self::GetItemProjected { .. }
+                | Self::GetValueRange { .. }
+                | Self::GetItemBinKey { .. }
+                | Self::Diagnostics { .. }
+                | Self::GetSchema { .. }
+                | Self::Warmup { .. }
+        )
+    }
+
+    // Requests that can change what's in "items", and so what a single-key
+    // get sees (see `DatabaseClient::epoch`).
+    fn changes_items(&self) -> bool {
+        matches!(
+            self,
+            Self::PutItem { .. }
+                | Self::PutItemDurable { .. }
+                | Self::PutIfChanged { .. }
+                | Self::PutItemReturning { .. }
+                | Self::PutItems { .. }
+                | Self::PutItemsIfAbsent { .. }
+                | Self::Apply { .. }
+                | Self::PutItemsBestEffort { .. }
+                | Self::Pop { .. }
+                | Self::Archive { .. }
+                | Self::DeleteRange { .. }
+                | Self::PopAny { .. }
+                | Self::DecrementFloor { .. }
+        )
+    }
+
+    // The new values this request would store, see
+    // `SpawnOptions::max_value_bytes`.
+    fn values_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Self::PutItem { item, .. }
+            | Self::PutItemDurable { item, .. }
+            | Self::PutIfChanged { item, .. }
+            | Self::PutItemReturning { item, .. } => vec![&mut item.value],
+            Self::PutItemBinKey { value, .. } => vec![value],
+            Self::PutItems { items, .. }
+            | Self::PutItemsIfAbsent { items, .. }
+            | Self::PutItemsBestEffort { items, .. } => {
+                items.iter_mut().map(|item| &mut item.value).collect()
+            }
+            Self::Apply { ops, .. } => ops
+                .iter_mut()
+                .filter_map(|op| match op {
+                    Op::Put { value, .. } => Some(value),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Shows up in logs, the slow-request log and the queue snapshot, so it gives
+// keys and sizes but never values: those can be large, and sensitive.
+impl std::fmt::Debug for DbRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::GetAll { .. } => f.debug_struct("GetAll").finish(),
+            Self::GetAllLossy { .. } => f.debug_struct("GetAllLossy").finish(),
+            Self::GetItem { key, .. } => f.debug_struct("GetItem").field("key", key).finish(),
+            Self::GetItems { keys, .. } => f
+                .debug_struct("GetItems")
+                .field("len", &keys.len())
+                .finish(),
+            Self::Exists { key, .. } => f.debug_struct("Exists").field("key", key).finish(),
+            Self::CountRead { key, .. } => f.debug_struct("CountRead").field("key", key).finish(),
+            Self::GetReadCount { key, .. } => {
+                f.debug_struct("GetReadCount").field("key", key).finish()
+            }
+            Self::GetPage {
+                page,
+                page_size,
+                with_total,
+                ..
+            } => f
+                .debug_struct("GetPage")
+                .field("page", page)
+                .field("page_size", page_size)
+                .field("with_total", with_total)
+                .finish(),
+            Self::Count { estimate, .. } => {
+                f.debug_struct("Count").field("estimate", estimate).finish()
+            }
+            Self::GetByTag { tag, .. } => f.debug_struct("GetByTag").field("tag", tag).finish(),
+            Self::GetTags { key, .. } => f.debug_struct("GetTags").field("key", key).finish(),
+            Self::AddTag { key, tag, .. } => f
+                .debug_struct("AddTag")
+                .field("key", key)
+                .field("tag", tag)
+                .finish(),
+            Self::RemoveTag { key, tag, .. } => f
+                .debug_struct("RemoveTag")
+                .field("key", key)
+                .field("tag", tag)
+                .finish(),
+            Self::GetByIndex { name, .. } => {
+                f.debug_struct("GetByIndex").field("name", name).finish()
+            }
+            Self::Query { filter, .. } => f.debug_struct("Query").field("filter", filter).finish(),
+            Self::Aggregate { prefix, op, .. } => f
+                .debug_struct("Aggregate")
+                .field("prefix", prefix)
+                .field("op", op)
+                .finish(),
+            Self::GetKeySizes { with_size, .. } => f
+                .debug_struct("GetKeySizes")
+                .field("with_size", with_size)
+                .finish(),
+            Self::Diff {
+                prefix_len,
+                client_buckets,
+                ..
+            } => f
+                .debug_struct("Diff")
+                .field("prefix_len", prefix_len)
+                .field("client_buckets", &client_buckets.len())
+                .finish(),
+            Self::TopBySize { n, .. } => f.debug_struct("TopBySize").field("n", n).finish(),
+            Self::ListPrefixes {
+                prefix, delimiter, ..
+            } => f
+                .debug_struct("ListPrefixes")
+                .field("prefix", prefix)
+                .field("delimiter", delimiter)
+                .finish(),
+            Self::FindDuplicates { min_count, .. } => f
+                .debug_struct("FindDuplicates")
+                .field("min_count", min_count)
+                .finish(),
+            Self::GetAllSortedByValue {
+                descending, limit, ..
+            } => f
+                .debug_struct("GetAllSortedByValue")
+                .field("descending", descending)
+                .field("limit", limit)
+                .finish(),
+            Self::GetWindow {
+                start_key,
+                count,
+                direction,
+                ..
+            } => f
+                .debug_struct("GetWindow")
+                .field("start_key", start_key)
+                .field("count", count)
+                .field("direction", direction)
+                .finish(),
+            Self::ForEach { .. } => f.debug_struct("ForEach").finish(),
+            Self::GetAllAttached { alias, .. } => f
+                .debug_struct("GetAllAttached")
+                .field("alias", alias)
+                .finish(),
+            Self::GetAttachedItem { alias, key, .. } => f
+                .debug_struct("GetAttachedItem")
+                .field("alias", alias)
+                .field("key", key)
+                .finish(),
+            Self::GetAllProjected { path, .. } => f
+                .debug_struct("GetAllProjected")
+                .field("path", path)
+                .finish(),
+            Self::GetItemProjected { key, path, .. } => f
+                .debug_struct("GetItemProjected")
+                .field("key", key)
+                .field("path", path)
+                .finish(),
+            Self::GetValueRange { key, range, .. } => f
+                .debug_struct("GetValueRange")
+                .field("key", key)
+                .field("range", range)
+                .finish(),
+            Self::GetItemBinKey { key, .. } => {
+                f.debug_struct("GetItemBinKey").field("key", key).finish()
+            }
+            Self::PutItemBinKey { key, .. } => {
+                f.debug_struct("PutItemBinKey").field("key", key).finish()
+            }
+            Self::PutItem { item, .. } => f
+                .debug_struct("PutItem")
+                .field("key", &item.key)
+                .field("value_len", &item.value.len())
+                .finish(),
+            Self::PutItemDurable { item, .. } => f
+                .debug_struct("PutItemDurable")
+                .field("key", &item.key)
+                .field("value_len", &item.value.len())
+                .finish(),
+            Self::PutIfChanged { item, .. } => f
+                .debug_struct("PutIfChanged")
+                .field("key", &item.key)
+                .field("value_len", &item.value.len())
+                .finish(),
+            Self::PutItemReturning {
+                item,
+                durable,
+                if_changed,
+                ..
+            } => f
+                .debug_struct("PutItemReturning")
+                .field("key", &item.key)
+                .field("value_len", &item.value.len())
+                .field("durable", durable)
+                .field("if_changed", if_changed)
+                .finish(),
+            Self::PutItems { items, .. } => f
+                .debug_struct("PutItems")
+                .field("len", &items.len())
+                .finish(),
+            Self::PutItemsIfAbsent { items, .. } => f
+                .debug_struct("PutItemsIfAbsent")
+                .field("len", &items.len())
+                .finish(),
+            Self::Apply { ops, .. } => f.debug_struct("Apply").field("len", &ops.len()).finish(),
+            Self::PutItemsBestEffort { items, .. } => f
+                .debug_struct("PutItemsBestEffort")
+                .field("len", &items.len())
+                .finish(),
+            Self::Pop { key, .. } => f.debug_struct("Pop").field("key", key).finish(),
+            Self::Archive {
+                from, to_prefix, ..
+            } => f
+                .debug_struct("Archive")
+                .field("from", from)
+                .field("to_prefix", to_prefix)
+                .finish(),
+            Self::DeleteRange { start, end, .. } => f
+                .debug_struct("DeleteRange")
+                .field("start", start)
+                .field("end", end)
+                .finish(),
+            Self::PopAny { .. } => f.debug_struct("PopAny").finish(),
+            Self::AcquireLeadership {
+                key,
+                candidate_id,
+                ttl,
+                ..
+            } => f
+                .debug_struct("AcquireLeadership")
+                .field("key", key)
+                .field("candidate_id", candidate_id)
+                .field("ttl", ttl)
+                .finish(),
+            Self::RenewLeadership {
+                key,
+                candidate_id,
+                ttl,
+                ..
+            } => f
+                .debug_struct("RenewLeadership")
+                .field("key", key)
+                .field("candidate_id", candidate_id)
+                .field("ttl", ttl)
+                .finish(),
+            Self::DecrementFloor {
+                key, delta, floor, ..
+            } => f
+                .debug_struct("DecrementFloor")
+                .field("key", key)
+                .field("delta", delta)
+                .field("floor", floor)
+                .finish(),
+            Self::Flush { .. } => f.debug_struct("Flush").finish(),
+            Self::Diagnostics { .. } => f.debug_struct("Diagnostics").finish(),
+            Self::GetSchema { .. } => f.debug_struct("GetSchema").finish(),
+            Self::Explain { query, .. } => f.debug_struct("Explain").field("query", query).finish(),
+            Self::Warmup { full, .. } => f.debug_struct("Warmup").field("full", full).finish(),
+            Self::Maintain {
+                incremental_vacuum, ..
+            } => f
+                .debug_struct("Maintain")
+                .field("incremental_vacuum", incremental_vacuum)
+                .finish(),
+            Self::Shutdown { .. } => f.debug_struct("Shutdown").finish(),
+        }
+    }
+}
+
+impl DatabaseClient {
+    fn new(
+        db_tx: mpsc::Sender<DbRequest>,
+        queued: Arc<QueuedRequests>,
+        shutting_down: Arc<AtomicBool>,
+        value_limit: Option<ValueLimit>,
+    ) -> Self {
+        Self {
+            db_tx,
+            queued,
+            shutting_down,
+            cache: Arc::default(),
+            in_flight_gets: Arc::default(),
+            epoch: Arc::default(),
+            totals: Arc::new(Totals {
+                started: Instant::now(),
+                requests: AtomicU64::new(0),
+                items_written: AtomicU64::new(0),
+            }),
+            value_limit,
+        }
+    }
+
+    fn wrote(&self, items: usize) {
+        self.totals
+            .items_written
+            .fetch_add(items as u64, Ordering::Relaxed);
+    }
+
+    async fn send(&self, mut request: DbRequest) -> anyhow::Result<()> {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            bail!(ShuttingDown);
+        }
+        if let Some(limit) = self.value_limit {
+            for value in request.values_mut() {
+                if value.len() <= limit.max {
+                    continue;
+                }
+                if !limit.truncate {
+                    bail!(ValueTooLarge {
+                        len: value.len(),
+                        limit: limit.max,
+                    });
+                }
+                truncate_value(value, limit.max);
+            }
+        }
+        let changes_items = request.changes_items();
+        self.enqueue(request).await?;
+        self.totals.requests.fetch_add(1, Ordering::Relaxed);
+        if changes_items {
+            self.epoch.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    // Sends `request`, noting it in `queued` in the same order the channel
+    // gets it.
+    async fn enqueue(&self, request: DbRequest) -> Result<(), ShuttingDown> {
+        let permit = self.db_tx.reserve().await.map_err(|_| ShuttingDown)?;
+        let description = format!("{request:?}");
+        let mut queued = self.queued.lock().unwrap_or_else(PoisonError::into_inner);
+        queued.push_back((description, Instant::now()));
+        permit.send(request);
+        Ok(())
+    }
+
+    /// The requests sent to the database thread that it hasn't picked up
+    /// yet, oldest first, with keys but no values. Doesn't go through the
+    /// thread, so it answers even when the thread is stuck.
+    pub fn queued(&self) -> Vec<QueuedRequest> {
+        self.queued
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(request, sent)| QueuedRequest {
+                request: request.clone(),
+                waiting_ms: sent.elapsed().as_millis() as u64,
+            })
+            .collect()
+    }
+
+    pub async fn get_all_items(&self) -> anyhow::Result<Vec<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetAll { respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Like [`Self::get_all_items`], but a row whose key or value can't be
+    /// read as a string (e.g. it isn't valid UTF-8) is logged and skipped
+    /// instead of failing the whole listing. Returns the remaining items and
+    /// how many rows were skipped.
+    pub async fn get_all_items_lossy(&self) -> anyhow::Result<(Vec<Item>, u64)> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetAllLossy { respond_to }).await?;
+
+        response.await?
+    }
+
+    pub async fn get_item(&self, key: String) -> anyhow::Result<Option<Item>> {
+        let (item, _) = self.get_item_with_cache_status(key).await?;
+        Ok(item)
+    }
+
+    /// Like [`Self::get_item`], but also says whether the answer came from
+    /// memory (see [`CacheStats`]) rather than the database.
+    pub async fn get_item_with_cache_status(
+        &self,
+        key: String,
+    ) -> anyhow::Result<(Option<Item>, bool)> {
+        let (item, cached) = self.get_item_shared(key).await?;
+        let counter = if cached {
+            &self.cache.hits
+        } else {
+            &self.cache.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok((item, cached))
+    }
+
+    // Lookups of the same key that overlap share one round trip to the
+    // database thread: the first one registers in `in_flight_gets`, and the
+    // rest wait for its answer instead of sending requests of their own.
+    async fn get_item_shared(&self, key: String) -> anyhow::Result<(Option<Item>, bool)> {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        let waiting = {
+            let mut in_flight = self
+                .in_flight_gets
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            match in_flight.get_mut(&key) {
+                Some((started, waiters)) if *started == epoch => {
+                    let (respond_to, response) = oneshot::channel();
+                    waiters.push(respond_to);
+                    Some(Some(response))
+                }
+                // Too old to share, and holding the key's place in the map.
+                Some(_) => Some(None),
+                None => {
+                    in_flight.insert(key.clone(), (epoch, Vec::new()));
+                    None
+                }
+            }
+        };
+        match waiting {
+            Some(Some(response)) => {
+                return match response.await {
+                    Ok(result) => result,
+                    // Whoever was looking it up gave up, so go ahead alone.
+                    Err(_) => self.get_item_once(key).await,
+                };
+            }
+            Some(None) => return self.get_item_once(key).await,
+            None => {}
+        }
+
+        let mut in_flight = InFlightGet {
+            in_flight: &self.in_flight_gets,
+            key: Some(key.clone()),
+        };
+        let result = self.get_item_once(key).await;
+        for waiter in in_flight.remove() {
+            let _ = waiter.send(match &result {
+                Ok(found) => Ok(found.clone()),
+                Err(err) => Err(copy_error(err)),
+            });
+        }
+        result
+    }
+
+    async fn get_item_once(&self, key: String) -> anyhow::Result<(Option<Item>, bool)> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetItem { key, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Counts of single-key lookups answered from memory and from the
+    /// database, since this client (or the one it was cloned from) was
+    /// created.
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.cache.hits.load(Ordering::Relaxed),
+            misses: self.cache.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Looks up several keys at once, all as of the same moment. The result
+    /// lines up with `keys`: one entry per key, in the same order, `None`
+    /// where the key is absent.
+    pub async fn get_items(&self, keys: Vec<String>) -> anyhow::Result<Vec<Option<Item>>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetItems { keys, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Like `get_items`, but returns only the keys that were found, mapped to
+    /// their values.
+    pub async fn get_items_map(
+        &self,
+        keys: Vec<String>,
+    ) -> anyhow::Result<HashMap<String, String>> {
+        let items = self.get_items(keys).await?;
+        Ok(items
+            .into_iter()
+            .flatten()
+            .map(|item| (item.key, item.value))
+            .collect())
+    }
+
+    /// Whether `key` is present, without reading its value.
+    pub async fn exists(&self, key: String) -> anyhow::Result<bool> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Exists { key, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Counts a read of `key` towards [`Self::get_read_count`]. The counts
+    /// are kept in memory on the database thread and written out in batches
+    /// at least every few seconds, so this is cheap, but the last few seconds'
+    /// worth are lost if the process dies before they're written (or if
+    /// writing them fails).
+    pub async fn count_read(&self, key: String) -> anyhow::Result<()> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::CountRead { key, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// How many reads of `key` have been counted with [`Self::count_read`].
+    pub async fn get_read_count(&self, key: String) -> anyhow::Result<u64> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetReadCount { key, respond_to })
+            .await?;
+
+        response.await?
+    }
+
+    /// Returns the `page`th (from zero) run of `page_size` items in key order.
+    /// With `with_total`, the page also carries the total item count, read in
+    /// the same transaction so the two agree with each other.
+    pub async fn get_page(
+        &self,
+        page: u32,
+        page_size: u32,
+        with_total: bool,
+    ) -> anyhow::Result<Page> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetPage {
+            page,
+            page_size,
+            with_total,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// The exact number of items. This scans the whole key index.
+    pub async fn count(&self) -> anyhow::Result<u64> {
+        self.count_inner(false).await
+    }
+
+    /// The number of items, in constant time, from a counter kept up to date
+    /// as items are written. It can only drift if something else writes to
+    /// the database file with the triggers that maintain it removed, and it
+    /// is corrected on every open. Falls back to an exact count if the
+    /// database has no counter (i.e. it was opened without
+    /// `create_if_missing`).
+    pub async fn count_estimate(&self) -> anyhow::Result<u64> {
+        self.count_inner(true).await
+    }
+
+    async fn count_inner(&self, estimate: bool) -> anyhow::Result<u64> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Count {
+            estimate,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// The items whose value for the index `name` (see
+    /// [`OpenOptions::indexes`]) equals `value`, in key order. `value` has to
+    /// match the indexed expression's type as well: the string "3" doesn't
+    /// match the number 3.
+    pub async fn get_by_index(
+        &self,
+        name: String,
+        value: serde_json::Value,
+    ) -> anyhow::Result<Vec<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetByIndex {
+            name,
+            value,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// The items tagged `tag`, in key order.
+    pub async fn get_by_tag(&self, tag: String) -> anyhow::Result<Vec<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetByTag { tag, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// The tags on `key`, in order, or `None` if there's no such item.
+    pub async fn get_tags(&self, key: String) -> anyhow::Result<Option<Vec<String>>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetTags { key, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Tags the item at `key` with `tag`, if there is such an item, which is
+    /// what the result says. Tagging an item twice with the same tag is the
+    /// same as tagging it once. Deleting an item removes its tags.
+    pub async fn add_tag(&self, key: String, tag: String) -> anyhow::Result<bool> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::AddTag {
+            key,
+            tag,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Takes `tag` off `key`. Returns whether it was there.
+    pub async fn remove_tag(&self, key: String, tag: String) -> anyhow::Result<bool> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::RemoveTag {
+            key,
+            tag,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// The items matching `filter`, in key order.
+    pub async fn query(&self, filter: Filter) -> anyhow::Result<Vec<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Query { filter, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Computes `op` over the values of the keys starting with `prefix`, read
+    /// as numbers: values that don't start with one count as 0. `None` when
+    /// no key matches, except for `Count`, which is then 0. This reads every
+    /// matching row.
+    pub async fn aggregate(&self, prefix: String, op: Aggregate) -> anyhow::Result<Option<f64>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Aggregate {
+            prefix,
+            op,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Every key, in order, without the values.
+    pub async fn get_keys(&self) -> anyhow::Result<Vec<String>> {
+        let keys = self.get_key_sizes_inner(false).await?;
+        Ok(keys.into_iter().map(|key| key.key).collect())
+    }
+
+    /// Every key, in order, with the size of its value in bytes but not the
+    /// value itself.
+    pub async fn get_key_sizes(&self) -> anyhow::Result<Vec<KeySize>> {
+        self.get_key_sizes_inner(true).await
+    }
+
+    async fn get_key_sizes_inner(&self, with_size: bool) -> anyhow::Result<Vec<KeySize>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetKeySizes {
+            with_size,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Compares bucket hashes from a client's copy of the store (see
+    /// [`crate::sync`]) with ours, and returns the buckets that differ, with
+    /// our hash, or `None` for buckets we don't have. Hashes are computed on
+    /// the spot, so this reads every row.
+    pub async fn diff(
+        &self,
+        prefix_len: usize,
+        client_buckets: HashMap<String, u64>,
+    ) -> anyhow::Result<BTreeMap<String, Option<u64>>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Diff {
+            prefix_len,
+            client_buckets,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// The `n` keys with the largest values, biggest first. This scans the
+    /// whole table.
+    pub async fn top_by_size(&self, n: u32) -> anyhow::Result<Vec<KeySize>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::TopBySize { n, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// The distinct "directories" one level below `prefix`, as in S3's list
+    /// with a delimiter: for each key that starts with `prefix` and has
+    /// `delimiter` somewhere after it, the key up to and including that first
+    /// `delimiter`, in order. E.g. with prefix `user:` and delimiter `:`, keys
+    /// `user:1:name` and `user:1:email` both give `user:1:`, and `user:2`
+    /// gives nothing.
+    pub async fn list_prefixes(
+        &self,
+        prefix: String,
+        delimiter: String,
+    ) -> anyhow::Result<Vec<String>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::ListPrefixes {
+            prefix,
+            delimiter,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Every value held by at least `min_count` keys (and always at least
+    /// two), with the keys holding it, most duplicated first. This scans the
+    /// whole table.
+    pub async fn find_duplicates(&self, min_count: u32) -> anyhow::Result<Vec<Duplicate>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::FindDuplicates {
+            min_count,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Up to `limit` items ordered by value (then key), smallest first unless
+    /// `descending`. Numeric values compare as numbers and come before all
+    /// the others (after them when `descending`), which compare as text.
+    /// Values aren't indexed, so this scans and sorts the whole table every
+    /// time; `limit` is capped at [`MAX_SORTED_BY_VALUE`] to keep at least the
+    /// result small.
+    pub async fn get_all_sorted_by_value(
+        &self,
+        descending: bool,
+        limit: u32,
+    ) -> anyhow::Result<Vec<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetAllSortedByValue {
+            descending,
+            limit,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Up to `count` items next to `start_key`, which needn't exist: going
+    /// [`Direction::Forward`], `start_key` and the keys after it; going
+    /// [`Direction::Backward`], the keys just before it, so the two windows
+    /// from the same anchor never overlap. Either way the items come back in
+    /// key order. Both are range scans on the key index.
+    pub async fn get_window(
+        &self,
+        start_key: String,
+        count: u32,
+        direction: Direction,
+    ) -> anyhow::Result<Vec<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetWindow {
+            start_key,
+            count,
+            direction,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Calls `f` with every item, in key order, on the database thread as each
+    /// row is read, so the items are never collected in memory. Returns how
+    /// many items were visited. Every other request waits while this runs, so
+    /// keep `f` cheap and hand anything slow off elsewhere (e.g. over a
+    /// channel).
+    pub async fn for_each_item(
+        &self,
+        f: impl FnMut(Item) + Send + 'static,
+    ) -> anyhow::Result<usize> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::ForEach {
+            f: Mutex::new(Box::new(f)),
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Lists the items in the database attached as `alias`.
+    pub async fn get_all_attached_items(&self, alias: String) -> anyhow::Result<Vec<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetAllAttached { alias, respond_to })
+            .await?;
+
+        response.await?
+    }
+
+    /// Reads `key` from the database attached as `alias`.
+    pub async fn get_attached_item(
+        &self,
+        alias: String,
+        key: String,
+    ) -> anyhow::Result<Option<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetAttachedItem {
+            alias,
+            key,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Like [`Self::get_all_items`], but each value is replaced by the JSON
+    /// found at `path` within it (as with SQLite's `json_extract`), encoded as
+    /// JSON text. Fails with [`NotJson`] if any value isn't JSON.
+    pub async fn get_all_items_projected(&self, path: String) -> anyhow::Result<Vec<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetAllProjected { path, respond_to })
+            .await?;
+
+        response.await?
+    }
+
+    /// Like [`Self::get_item`], but the value is replaced by the JSON found at
+    /// `path` within it, encoded as JSON text (`null` if there's nothing
+    /// there). Fails with [`NotJson`] if the value isn't JSON.
+    pub async fn get_item_projected(
+        &self,
+        key: String,
+        path: String,
+    ) -> anyhow::Result<Option<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetItemProjected {
+            key,
+            path,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Reads just `range` of the bytes of `key`'s value, without loading the
+    /// rest. The slice can start or end partway through a UTF-8 character.
+    /// Fails with [`RangeNotSatisfiable`] if the range starts past the end of
+    /// the value.
+    pub async fn get_value_range(
+        &self,
+        key: String,
+        range: ByteRange,
+    ) -> anyhow::Result<Option<ValueSlice>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetValueRange {
+            key,
+            range,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Gets the value stored under a binary key. Binary keys live apart from
+    /// text keys, in their own table, so they never collide or mix in
+    /// listings.
+    pub async fn get_item_bin_key(&self, key: Vec<u8>) -> anyhow::Result<Option<String>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetItemBinKey { key, respond_to })
+            .await?;
+
+        response.await?
+    }
+
+    pub async fn put_item_bin_key(&self, key: Vec<u8>, value: String) -> anyhow::Result<()> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::PutItemBinKey {
+            key,
+            value,
+            respond_to,
+        })
+        .await?;
+
+        response.await??;
+        self.wrote(1);
+        Ok(())
+    }
+
+    pub async fn put_item(&self, item: Item) -> anyhow::Result<()> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::PutItem { item, respond_to }).await?;
+
+        response.await??;
+        self.wrote(1);
+        Ok(())
+    }
+
+    /// Like `put_item`, but the write is committed with `synchronous = FULL`
+    /// whatever the connection's setting, so once this returns it survives a
+    /// crash or power loss. Never coalesced with other writes.
+    pub async fn put_item_durable(&self, item: Item) -> anyhow::Result<()> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::PutItemDurable { item, respond_to })
+            .await?;
+
+        response.await??;
+        self.wrote(1);
+        Ok(())
+    }
+
+    /// Like `put_item`, but leaves the row alone if it already holds exactly
+    /// this value. Returns whether anything was written.
+    pub async fn put_item_if_changed(&self, item: Item) -> anyhow::Result<bool> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::PutIfChanged { item, respond_to })
+            .await?;
+
+        let written = response.await??;
+        self.wrote(written as usize);
+        Ok(written)
+    }
+
+    /// Like `put_item`, or `put_item_durable` or `put_item_if_changed` as the
+    /// flags say, but returns the row as it was stored (after any
+    /// `SpawnOptions::max_value_bytes` truncation), and whether anything was
+    /// written.
+    pub async fn put_item_returning(
+        &self,
+        item: Item,
+        durable: bool,
+        if_changed: bool,
+    ) -> anyhow::Result<(Item, bool)> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::PutItemReturning {
+            item,
+            durable,
+            if_changed,
+            respond_to,
+        })
+        .await?;
+
+        let (item, written) = response.await??;
+        self.wrote(written as usize);
+        Ok((item, written))
+    }
+
+    /// Writes all of `items` in a single transaction.
+    pub async fn put_items(&self, items: Vec<Item>) -> anyhow::Result<()> {
+        let (respond_to, response) = oneshot::channel();
+
+        let count = items.len();
+        self.send(DbRequest::PutItems { items, respond_to }).await?;
+
+        response.await??;
+        self.wrote(count);
+        Ok(())
+    }
+
+    /// Like `put_items`, but skips the items whose keys already exist rather
+    /// than overwriting them. Returns how many were written.
+    pub async fn put_items_if_absent(&self, items: Vec<Item>) -> anyhow::Result<usize> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::PutItemsIfAbsent { items, respond_to })
+            .await?;
+
+        let written = response.await??;
+        self.wrote(written);
+        Ok(written)
+    }
+
+    /// Applies `ops` in order, all in one transaction: either every op takes
+    /// effect or, if any fails (including a `CheckEquals` that doesn't hold,
+    /// reported as [`CheckFailed`]), none do.
+    pub async fn apply(&self, ops: Vec<Op>) -> anyhow::Result<()> {
+        let (respond_to, response) = oneshot::channel();
+
+        let puts = ops.iter().filter(|op| matches!(op, Op::Put { .. })).count();
+        self.send(DbRequest::Apply { ops, respond_to }).await?;
+
+        response.await??;
+        self.wrote(puts);
+        Ok(())
+    }
+
+    /// Like [`Self::put_items`], but a failure only discards the item that
+    /// caused it: each item is written under its own savepoint, and the
+    /// result has one entry per item, in order, saying whether it landed.
+    pub async fn put_items_best_effort(
+        &self,
+        items: Vec<Item>,
+    ) -> anyhow::Result<Vec<Result<(), String>>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::PutItemsBestEffort { items, respond_to })
+            .await?;
+
+        let results = response.await??;
+        self.wrote(results.iter().filter(|result| result.is_ok()).count());
+        Ok(results)
+    }
+
+    /// Returns a sender for pipelined ingestion. Items pushed into it are
+    /// written in transactions of up to `batch_size` items (fewer if the
+    /// sender isn't keeping the channel full). Once every clone of the sender
+    /// is dropped and the remaining items are committed, the receiver resolves
+    /// to the total number of items written, or to the first error, after
+    /// which nothing more is written.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn put_stream(
+        &self,
+        batch_size: usize,
+    ) -> (mpsc::Sender<Item>, oneshot::Receiver<anyhow::Result<usize>>) {
+        let batch_size = batch_size.max(1);
+        let (item_tx, mut item_rx) = mpsc::channel(batch_size);
+        let (done_tx, done_rx) = oneshot::channel();
+        let client = self.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let mut written = 0;
+                let mut batch = Vec::with_capacity(batch_size);
+                while item_rx.recv_many(&mut batch, batch_size).await > 0 {
+                    written += batch.len();
+                    client.put_items(std::mem::take(&mut batch)).await?;
+                }
+                Ok::<_, anyhow::Error>(written)
+            }
+            .await;
+            let _ = done_tx.send(result);
+        });
+        (item_tx, done_rx)
+    }
+
+    /// Removes `key` and returns the item it held, if any. Two callers racing
+    /// to pop the same key can't both get it.
+    pub async fn pop(&self, key: String) -> anyhow::Result<Option<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Pop { key, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Moves `from` to `to_prefix + from + ":" + <milliseconds since the
+    /// epoch>`, e.g. `users/1` to `archive/users/1:1700000000000`, in one
+    /// transaction, and returns the item under its new key. Returns `None`,
+    /// and changes nothing, if `from` doesn't exist. Fails rather than
+    /// overwrite an earlier archive that landed on the same key.
+    pub async fn archive(&self, from: String, to_prefix: String) -> anyhow::Result<Option<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Archive {
+            from,
+            to_prefix,
+            respond_to,
+        })
+        .await?;
+
+        let archived = response.await??;
+        self.wrote(usize::from(archived.is_some()));
+        Ok(archived)
+    }
+
+    /// Deletes every key in `[start, end)`, in key order, and returns how
+    /// many were deleted.
+    pub async fn delete_range(&self, start: String, end: String) -> anyhow::Result<usize> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::DeleteRange {
+            start,
+            end,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Removes and returns the item with the lexically smallest key, for
+    /// using the store as a rough FIFO queue.
+    pub async fn pop_any(&self) -> anyhow::Result<Option<Item>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::PopAny { respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Makes `candidate_id` the leader for `key` for the next `ttl`, unless
+    /// someone else holds an unexpired lease on it. A candidate that already
+    /// holds the lease gets it extended. Returns the outcome and whoever
+    /// leads now. Leases live in their own table, not among the items.
+    pub async fn acquire_leadership(
+        &self,
+        key: String,
+        candidate_id: String,
+        ttl: Duration,
+    ) -> anyhow::Result<Leadership> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::AcquireLeadership {
+            key,
+            candidate_id,
+            ttl,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Extends `candidate_id`'s lease on `key` to `ttl` from now, but only if
+    /// it still holds it: a lease that has run out has to be acquired again,
+    /// since someone else may have led in between.
+    pub async fn renew_leadership(
+        &self,
+        key: String,
+        candidate_id: String,
+        ttl: Duration,
+    ) -> anyhow::Result<Leadership> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::RenewLeadership {
+            key,
+            candidate_id,
+            ttl,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Takes `delta` off the integer stored at `key` (a missing key counts as
+    /// 0), but stops at `floor`, all in one transaction. Returns the new
+    /// value and whether the floor stopped it short. A value already below
+    /// `floor` is left where it is. Fails with [`NotAnInteger`] if the value
+    /// isn't one.
+    pub async fn decrement_floor(
+        &self,
+        key: String,
+        delta: i64,
+        floor: i64,
+    ) -> anyhow::Result<(i64, bool)> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::DecrementFloor {
+            key,
+            delta,
+            floor,
+            respond_to,
+        })
+        .await?;
+
+        let result = response.await??;
+        self.wrote(1);
+        Ok(result)
+    }
+
+    /// A durability barrier: once this returns, every write sent before it
+    /// (whether or not its caller has heard back yet) is on disk, whatever
+    /// `synchronous` is set to. Requests are handled in order, so this only
+    /// runs after the earlier writes have committed.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Flush { respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Gathers a troubleshooting bundle: settings, sizes, an integrity check
+    /// and recent slow requests. The integrity check reads the whole
+    /// database, so this can take a while on a large one.
+    pub async fn diagnostics(&self) -> anyhow::Result<Diagnostics> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Diagnostics { respond_to }).await?;
+
+        response.await?
+    }
+
+    /// The DDL of the "items" table and of the indexes and triggers on it,
+    /// table first.
+    pub async fn get_schema(&self) -> anyhow::Result<Vec<SchemaObject>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::GetSchema { respond_to }).await?;
+
+        response.await?
+    }
+
+    /// SQLite's plan for `query`, to check which index (if any) it uses. The
+    /// query itself isn't run.
+    pub async fn explain(&self, query: Explain) -> anyhow::Result<Vec<PlanStep>> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Explain { query, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Pulls pages into SQLite's (and the OS's) cache so that the first real
+    /// requests don't pay for cold reads. The cheap version walks the primary
+    /// key index; `full` also reads every value.
+    pub async fn warmup(&self, full: bool) -> anyhow::Result<()> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Warmup { full, respond_to }).await?;
+
+        response.await?
+    }
+
+    /// Routine upkeep: checkpoints the WAL (in WAL mode) and truncates it,
+    /// then lets SQLite refresh its query planner statistics with `PRAGMA
+    /// optimize`. With `incremental_vacuum`, also returns free pages to the
+    /// filesystem, which only does anything if the database was created with
+    /// `auto_vacuum = INCREMENTAL`.
+    pub async fn maintain(&self, incremental_vacuum: bool) -> anyhow::Result<()> {
+        let (respond_to, response) = oneshot::channel();
+
+        self.send(DbRequest::Maintain {
+            incremental_vacuum,
+            respond_to,
+        })
+        .await?;
+
+        response.await?
+    }
+
+    /// Closes the connection and stops the database thread, then logs one
+    /// summary of this client's lifetime: requests sent, items written,
+    /// uptime, and whether the connection closed cleanly.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        let (respond_to, response) = oneshot::channel();
+
+        // From here on, anything else sent fails with ShuttingDown; this
+        // request is the one exception.
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.enqueue(DbRequest::Shutdown { respond_to }).await?;
+
+        let result = response.await.unwrap_or_else(|err| Err(err.into()));
+        tracing::info!(
+            requests = self.totals.requests.load(Ordering::Relaxed),
+            items_written = self.totals.items_written.load(Ordering::Relaxed),
+            uptime_secs = self.totals.started.elapsed().as_secs(),
+            closed_cleanly = result.is_ok(),
+            "database shut down"
+        );
+        result
+    }
+}
+
+// This is an abomination: an async function that does a ton of blocking I/O.
+// This should only be run in a dedicated runtime, or on a blocking-pool thread.
+async fn database_thread(
+    mut conn: Connection,
+    mut db_rx: Requests,
+    options: SpawnOptions,
+    shutting_down: Arc<AtomicBool>,
+) {
+    // A request that was pulled off the channel while collecting a batch, but
+    // didn't belong in it. It is handled before anything else is received.
+    let mut pending = None;
+
+    let row_limit = RowLimit {
+        max: options.max_result_rows,
+        clamp: options.clamp_result_rows,
+    };
+
+    let mut bloom = None;
+    if options.bloom_filter {
+        bloom = build_bloom(&conn, BLOOM_MIN_CAPACITY);
+    }
+
+    let mut health = Health::default();
+    let mut slow_requests = VecDeque::with_capacity(SLOW_REQUEST_LOG_LEN);
+    let mut read_counts = HashMap::new();
+    // When the oldest buffered read count is due to be written.
+    let mut read_counts_due = tokio::time::Instant::now();
+    // Whether `PRAGMA query_only` is on, see `SpawnOptions::query_only_reads`.
+    let mut query_only = false;
+
+    // Listen for database requests
+    loop {
+        let (request, description) = match pending.take() {
+            Some(pending) => pending,
+            None => {
+                let received = if read_counts.is_empty() {
+                    db_rx.recv().await
+                } else {
+                    match tokio::time::timeout_at(read_counts_due, db_rx.recv()).await {
+                        Ok(received) => received,
+                        Err(_) => {
+                            flush_read_counts(&conn, &mut read_counts, &mut query_only);
+                            // Anything left failed to write; try again later
+                            // rather than straight away.
+                            read_counts_due = tokio::time::Instant::now() + READ_COUNT_MAX_AGE;
+                            continue;
+                        }
+                    }
+                };
+                match received {
+                    Some(received) => received,
+                    None => {
+                        flush_read_counts(&conn, &mut read_counts, &mut query_only);
+                        break;
+                    }
+                }
+            }
+        };
+        tracing::debug!(request = description, "recv");
+        let started = Instant::now();
+        if options.query_only_reads && request.is_read_only() != query_only {
+            match conn.pragma_update(None, "query_only", !query_only) {
+                Ok(()) => query_only = !query_only,
+                Err(err) => tracing::error!(?err, "failed to switch query_only"),
+            }
+        }
+        match request {
+            DbRequest::GetAll { respond_to } => {
+                let result = get_all_items_db(&conn, row_limit);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetAllLossy { respond_to } => {
+                let result = get_all_items_lossy_db(&conn, row_limit);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetItem { key, respond_to } => match options.read_coalesce_window {
+                // A miss in the bloom filter says nothing about the
+                // connection, so it's answered without touching `health`.
+                None if !bloom_might_contain(&bloom, &key) => {
+                    let _ = respond_to.send(Ok((None, true)));
+                }
+                None => {
+                    let result = get_item_db(&conn, key).map(|item| (item, false));
+                    reply(&mut health, respond_to, result);
+                }
+                Some(window) => {
+                    let mut batch = vec![(key, respond_to)];
+                    pending =
+                        collect_batch(&mut db_rx, window, &mut batch, |request| match request {
+                            DbRequest::GetItem { key, respond_to } => Ok((key, respond_to)),
+                            other => Err(other),
+                        })
+                        .await;
+                    get_items_coalesced_db(&conn, &mut health, &bloom, batch);
+                }
+            },
+            DbRequest::GetItems { keys, respond_to } => {
+                let result = get_items_db(&conn, &bloom, keys);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Exists { key, respond_to } => {
+                if bloom_might_contain(&bloom, &key) {
+                    let result = exists_db(&conn, key);
+                    reply(&mut health, respond_to, result);
+                } else {
+                    let _ = respond_to.send(Ok(false));
+                }
+            }
+            DbRequest::CountRead { key, respond_to } => {
+                if read_counts.is_empty() {
+                    read_counts_due = tokio::time::Instant::now() + READ_COUNT_MAX_AGE;
+                }
+                *read_counts.entry(key).or_insert(0) += 1;
+                let result = if read_counts.len() >= READ_COUNT_BATCH {
+                    write_read_counts_db(&conn, &mut read_counts)
+                } else {
+                    Ok(())
+                };
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetReadCount { key, respond_to } => {
+                let result = write_read_counts_db(&conn, &mut read_counts)
+                    .and_then(|()| get_read_count_db(&conn, key));
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetPage {
+                page,
+                page_size,
+                with_total,
+                respond_to,
+            } => {
+                let result = get_page_db(&conn, row_limit, page, page_size, with_total);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Count {
+                estimate,
+                respond_to,
+            } => {
+                let result = count_db(&conn, estimate);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetByIndex {
+                name,
+                value,
+                respond_to,
+            } => {
+                let result = get_by_index_db(&conn, row_limit, name, value);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetByTag { tag, respond_to } => {
+                let result = get_by_tag_db(&conn, row_limit, tag);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetTags { key, respond_to } => {
+                let result = get_tags_db(&conn, key);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::AddTag {
+                key,
+                tag,
+                respond_to,
+            } => {
+                let result = add_tag_db(&conn, key, tag);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::RemoveTag {
+                key,
+                tag,
+                respond_to,
+            } => {
+                let result = remove_tag_db(&conn, key, tag);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Query { filter, respond_to } => {
+                let result = query_db(&conn, row_limit, &filter);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Aggregate {
+                prefix,
+                op,
+                respond_to,
+            } => {
+                let result = aggregate_db(&conn, prefix, op);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetKeySizes {
+                with_size,
+                respond_to,
+            } => {
+                let result = get_key_sizes_db(&conn, row_limit, with_size);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Diff {
+                prefix_len,
+                client_buckets,
+                respond_to,
+            } => {
+                let result = bucket_hashes_db(&conn, prefix_len)
+                    .map(|ours| crate::sync::diff(&ours, &client_buckets));
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::TopBySize { n, respond_to } => {
+                let result = top_by_size_db(&conn, row_limit, n);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::ListPrefixes {
+                prefix,
+                delimiter,
+                respond_to,
+            } => {
+                let result = list_prefixes_db(&conn, row_limit, prefix, delimiter);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::FindDuplicates {
+                min_count,
+                respond_to,
+            } => {
+                let result = find_duplicates_db(&conn, row_limit, min_count);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetAllSortedByValue {
+                descending,
+                limit,
+                respond_to,
+            } => {
+                let result = get_all_sorted_by_value_db(&conn, row_limit, descending, limit);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetWindow {
+                start_key,
+                count,
+                direction,
+                respond_to,
+            } => {
+                let result = get_window_db(&conn, row_limit, start_key, count, direction);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::ForEach { f, respond_to } => {
+                let mut f = f.into_inner().unwrap_or_else(PoisonError::into_inner);
+                // The callback is arbitrary caller code; don't let it take
+                // the database thread down with it.
+                let result =
+                    std::panic::catch_unwind(AssertUnwindSafe(|| for_each_item_db(&conn, &mut *f)))
+                        .unwrap_or_else(|_| Err(anyhow!("for_each_item callback panicked")));
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetAllAttached { alias, respond_to } => {
+                let result = get_all_attached_items_db(&conn, row_limit, alias);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetAttachedItem {
+                alias,
+                key,
+                respond_to,
+            } => {
+                let result = get_attached_item_db(&conn, alias, key);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetAllProjected { path, respond_to } => {
+                let result = get_all_items_projected_db(&conn, row_limit, path);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetItemProjected {
+                key,
+                path,
+                respond_to,
+            } => {
+                let result = get_item_projected_db(&conn, key, path);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetValueRange {
+                key,
+                range,
+                respond_to,
+            } => {
+                let result = get_value_range_db(&conn, key, range);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetItemBinKey { key, respond_to } => {
+                let result = get_item_bin_key_db(&conn, key);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::PutItemBinKey {
+                key,
+                value,
+                respond_to,
+            } => {
+                let result = put_item_bin_key_db(&conn, key, value);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::PutItem { item, respond_to } => match options.write_coalesce_window {
+                None => {
+                    bloom_insert(&mut bloom, [item.key.as_str()]);
+                    let result = put_item_db(&conn, item);
+                    reply(&mut health, respond_to, result);
+                    bloom_maybe_grow(&conn, &mut bloom);
+                }
+                Some(window) => {
+                    let mut batch = vec![(item, respond_to)];
+                    pending =
+                        collect_batch(&mut db_rx, window, &mut batch, |request| match request {
+                            DbRequest::PutItem { item, respond_to } => Ok((item, respond_to)),
+                            other => Err(other),
+                        })
+                        .await;
+                    bloom_insert(&mut bloom, batch.iter().map(|(item, _)| item.key.as_str()));
+                    put_items_coalesced_db(&conn, &mut health, batch);
+                    bloom_maybe_grow(&conn, &mut bloom);
+                }
+            },
+            DbRequest::PutItemDurable { item, respond_to } => {
+                bloom_insert(&mut bloom, [item.key.as_str()]);
+                let result = put_item_durable_db(&conn, item);
+                reply(&mut health, respond_to, result);
+                bloom_maybe_grow(&conn, &mut bloom);
+            }
+            DbRequest::PutIfChanged { item, respond_to } => {
+                bloom_insert(&mut bloom, [item.key.as_str()]);
+                let result = put_item_if_changed_db(&conn, item);
+                reply(&mut health, respond_to, result);
+                bloom_maybe_grow(&conn, &mut bloom);
+            }
+            DbRequest::PutItemReturning {
+                item,
+                durable,
+                if_changed,
+                respond_to,
+            } => {
+                bloom_insert(&mut bloom, [item.key.as_str()]);
+                let result = if durable {
+                    durably(&conn, |conn| put_item_returning_db(conn, item, if_changed))
+                } else {
+                    put_item_returning_db(&conn, item, if_changed)
+                };
+                reply(&mut health, respond_to, result);
+                bloom_maybe_grow(&conn, &mut bloom);
+            }
+            DbRequest::PutItems { items, respond_to } => {
+                bloom_insert(&mut bloom, items.iter().map(|item| item.key.as_str()));
+                let result = put_items_db(&conn, items);
+                reply(&mut health, respond_to, result);
+                bloom_maybe_grow(&conn, &mut bloom);
+            }
+            DbRequest::PutItemsIfAbsent { items, respond_to } => {
+                bloom_insert(&mut bloom, items.iter().map(|item| item.key.as_str()));
+                let result = put_items_if_absent_db(&conn, items);
+                reply(&mut health, respond_to, result);
+                bloom_maybe_grow(&conn, &mut bloom);
+            }
+            DbRequest::Apply { ops, respond_to } => {
+                bloom_insert(
+                    &mut bloom,
+                    ops.iter().filter_map(|op| match op {
+                        Op::Put { key, .. } => Some(key.as_str()),
+                        _ => None,
+                    }),
+                );
+                let result = apply_db(&conn, ops);
+                reply(&mut health, respond_to, result);
+                bloom_maybe_grow(&conn, &mut bloom);
+            }
+            DbRequest::PutItemsBestEffort { items, respond_to } => {
+                bloom_insert(&mut bloom, items.iter().map(|item| item.key.as_str()));
+                let result = put_items_best_effort_db(&conn, items);
+                reply(&mut health, respond_to, result);
+                bloom_maybe_grow(&conn, &mut bloom);
+            }
+            DbRequest::Pop { key, respond_to } => {
+                let result = pop_db(&conn, key);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Archive {
+                from,
+                to_prefix,
+                respond_to,
+            } => {
+                // Named here rather than in `archive_db`, so the bloom filter
+                // hears about the new key before it exists.
+                let to = format!("{to_prefix}{from}:{}", now_ms());
+                bloom_insert(&mut bloom, [to.as_str()]);
+                let result = archive_db(&conn, from, to);
+                reply(&mut health, respond_to, result);
+                bloom_maybe_grow(&conn, &mut bloom);
+            }
+            DbRequest::DeleteRange {
+                start,
+                end,
+                respond_to,
+            } => {
+                let result = delete_range_db(&conn, start, end);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::PopAny { respond_to } => {
+                let result = pop_any_db(&conn);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::AcquireLeadership {
+                key,
+                candidate_id,
+                ttl,
+                respond_to,
+            } => {
+                let result = acquire_leadership_db(&conn, key, candidate_id, ttl);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::RenewLeadership {
+                key,
+                candidate_id,
+                ttl,
+                respond_to,
+            } => {
+                let result = renew_leadership_db(&conn, key, candidate_id, ttl);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::DecrementFloor {
+                key,
+                delta,
+                floor,
+                respond_to,
+            } => {
+                // A missing key is created, so the filter has to hear about it.
+                bloom_insert(&mut bloom, [key.as_str()]);
+                let result = decrement_floor_db(&conn, key, delta, floor);
+                reply(&mut health, respond_to, result);
+                bloom_maybe_grow(&conn, &mut bloom);
+            }
+            DbRequest::Flush { respond_to } => {
+                let result = flush_db(&conn);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Diagnostics { respond_to } => {
+                let result = diagnostics_db(&conn, &slow_requests);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::GetSchema { respond_to } => {
+                let result = get_schema_db(&conn);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Explain { query, respond_to } => {
+                let result = explain_db(&conn, row_limit, query);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Warmup { full, respond_to } => {
+                let result = warmup_db(&conn, full);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Maintain {
+                incremental_vacuum,
+                respond_to,
+            } => {
+                let result = maintain_db(&conn, incremental_vacuum);
+                reply(&mut health, respond_to, result);
+            }
+            DbRequest::Shutdown { respond_to } => {
+                if let Err(err) = write_read_counts_db(&conn, &mut read_counts) {
+                    tracing::error!(?err, "failed to write read counts");
+                }
+                let _ = respond_to.send(shutdown(conn));
+                break;
+            }
+        }
+
+        let elapsed = started.elapsed();
+        if elapsed >= SLOW_REQUEST {
+            tracing::debug!(request = description, ?elapsed, "slow request");
+            if slow_requests.len() == SLOW_REQUEST_LOG_LEN {
+                slow_requests.pop_front();
+            }
+            slow_requests.push_back(SlowRequest {
+                request: description,
+                elapsed_ms: elapsed.as_millis() as u64,
+            });
+        }
+
+        if let Some(reopen) = &options.reopen {
+            if health.fatal_streak >= reopen.after_errors.max(1) {
+                if health.reopens >= reopen.max_reopens {
+                    tracing::error!(
+                        reopens = health.reopens,
+                        "database connection is still failing, giving up"
+                    );
+                    break;
+                }
+                health.fatal_streak = 0;
+                health.reopens += 1;
+                tracing::warn!(attempt = health.reopens, "reopening database connection");
+                match open_with_options(reopen.path.clone(), reopen.options.clone()) {
+                    Ok(new_conn) => {
+                        let old_conn = std::mem::replace(&mut conn, new_conn);
+                        query_only = false;
+                        if let Err((_, err)) = old_conn.close() {
+                            tracing::warn!(?err, "failed to close the old connection");
+                        }
+                    }
+                    Err(err) => tracing::error!("failed to reopen database: {err:#}"),
+                }
+            }
+        }
+    }
+    shutting_down.store(true, Ordering::SeqCst);
+}
+
+// Counts the errors that suggest the connection itself is broken, for
+// `SpawnOptions::reopen`. Any successful request resets both counts.
+#[derive(Default)]
+struct Health {
+    fatal_streak: u32,
+    reopens: u32,
+}
+
+impl Health {
+    fn record<T>(&mut self, result: &anyhow::Result<T>) {
+        match result {
+            Ok(_) => *self = Self::default(),
+            Err(err) if is_fatal(err) => self.fatal_streak += 1,
+            Err(_) => {}
+        }
+    }
+}
+
+fn reply<T>(
+    health: &mut Health,
+    respond_to: oneshot::Sender<anyhow::Result<T>>,
+    result: anyhow::Result<T>,
+) {
+    health.record(&result);
+    let _ = respond_to.send(result);
+}
+
+// Errors that no retry of the same request on the same connection will fix.
+fn is_fatal(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        matches!(
+            cause
+                .downcast_ref::<rusqlite::Error>()
+                .and_then(rusqlite::Error::sqlite_error_code),
+            Some(
+                rusqlite::ErrorCode::SystemIoFailure
+                    | rusqlite::ErrorCode::DatabaseCorrupt
+                    | rusqlite::ErrorCode::NotADatabase
+                    | rusqlite::ErrorCode::CannotOpen
+            )
+        )
+    })
+}
+
+// Keeps receiving requests until `window` elapses, moving each one that
+// `accept` claims into `batch`. Stops early at the first request that doesn't
+// belong, and returns it with its description so the caller can handle it
+// next; this keeps requests in the order they were sent.
+async fn collect_batch<T>(
+    db_rx: &mut Requests,
+    window: Duration,
+    batch: &mut Vec<T>,
+    accept: impl Fn(DbRequest) -> Result<T, DbRequest>,
+) -> Option<(DbRequest, String)> {
+    let deadline = tokio::time::Instant::now() + window;
+    while batch.len() < MAX_COALESCED_REQUESTS {
+        let (request, description) = match tokio::time::timeout_at(deadline, db_rx.recv()).await {
+            Ok(Some(received)) => received,
+            // Either the window elapsed or every client has gone away.
+            Ok(None) | Err(_) => return None,
+        };
+        tracing::debug!(request = description, "recv");
+        match accept(request) {
+            Ok(item) => batch.push(item),
+            Err(other) => return Some((other, description)),
+        }
+    }
+    None
+}
+
+#[derive(Clone, Copy)]
+struct RowLimit {
+    max: Option<usize>,
+    clamp: bool,
+}
+
+impl RowLimit {
+    // What to put in a listing's LIMIT: one more than the cap, so going over
+    // it can be told apart from hitting it exactly. SQLite treats a negative
+    // LIMIT as no limit at all.
+    fn sql(self) -> i64 {
+        self.max.map_or(-1, |max| max as i64 + 1)
+    }
+
+    fn apply<T>(self, mut rows: Vec<T>) -> anyhow::Result<Vec<T>> {
+        match self.max {
+            Some(max) if rows.len() > max => {
+                if !self.clamp {
+                    bail!(ResultTooLarge { limit: max });
+                }
+                rows.truncate(max);
+                Ok(rows)
+            }
+            _ => Ok(rows),
+        }
+    }
+
+    fn page_size(self, requested: u32) -> anyhow::Result<u32> {
+        match self.max {
+            Some(max) if requested as usize > max => {
+                if !self.clamp {
+                    bail!(ResultTooLarge { limit: max });
+                }
+                Ok(max as u32)
+            }
+            _ => Ok(requested),
+        }
+    }
+}
+
+// Keys are added to the filter before they're written rather than after, so a
+// failed write leaves a harmless false positive instead of needing a clone of
+// every key. Nothing is ever removed: deleted keys are false positives too.
+fn bloom_insert<'a>(bloom: &mut Option<BloomFilter>, keys: impl IntoIterator<Item = &'a str>) {
+    if let Some(filter) = bloom {
+        for key in keys {
+            filter.insert(key);
+        }
+    }
+}
+
+fn bloom_might_contain(bloom: &Option<BloomFilter>, key: &str) -> bool {
+    bloom
+        .as_ref()
+        .is_none_or(|filter| filter.might_contain(key))
+}
+
+// Once a filter has seen more inserts than it was sized for, its false
+// positive rate climbs, so rebuild it from the table at twice the size. This
+// has to happen after the write it follows, or the rebuild would miss its keys.
+fn bloom_maybe_grow(conn: &Connection, bloom: &mut Option<BloomFilter>) {
+    if let Some(filter) = bloom {
+        if filter.inserted() > filter.capacity() {
+            let capacity = filter.capacity() * 2;
+            *bloom = build_bloom(conn, capacity);
+        }
+    }
+}
+
+fn build_bloom(conn: &Connection, min_capacity: usize) -> Option<BloomFilter> {
+    match build_bloom_db(conn, min_capacity) {
+        Ok(filter) => {
+            tracing::info!(capacity = filter.capacity(), "built bloom filter");
+            Some(filter)
+        }
+        Err(err) => {
+            tracing::error!(?err, "failed to build bloom filter, disabling it");
+            None
+        }
+    }
+}
+
+// Database operation functions
+fn build_bloom_db(conn: &Connection, min_capacity: usize) -> anyhow::Result<BloomFilter> {
+    let count: i64 = conn.query_row("SELECT count(*) FROM items", [], |row| row.get(0))?;
+    let capacity = (count as usize * 2).max(min_capacity);
+    let mut filter = BloomFilter::with_capacity(capacity, BLOOM_FALSE_POSITIVE_RATE);
+
+    let mut stmt = conn.prepare("SELECT key FROM items")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        filter.insert(&row.get::<_, String>(0)?);
+    }
+    Ok(filter)
+}
+
+fn get_all_items_db(conn: &Connection, row_limit: RowLimit) -> anyhow::Result<Vec<Item>> {
+    let mut stmt = conn.prepare("SELECT key, value FROM items LIMIT ?1")?;
+    let item_iter = stmt.query_map([row_limit.sql()], |row| {
+        Ok(Item {
+            key: row.get(0)?,
+            value: row.get(1)?,
+        })
+    })?;
+
+    let mut items = Vec::new();
+    for item in item_iter {
+        items.push(item?);
+    }
+    row_limit.apply(items)
+}
+
+fn get_all_items_lossy_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+) -> anyhow::Result<(Vec<Item>, u64)> {
+    let mut stmt = conn.prepare("SELECT rowid, key, value FROM items LIMIT ?1")?;
+    let mut rows = stmt.query([row_limit.sql()])?;
+
+    let mut items = Vec::new();
+    let mut skipped = 0;
+    while let Some(row) = rows.next()? {
+        let item = row.get(1).and_then(|key| {
+            Ok(Item {
+                key,
+                value: row.get(2)?,
+            })
+        });
+        match item {
+            Ok(item) => items.push(item),
+            // Only a row that doesn't decode is skipped; anything else means
+            // the listing itself went wrong.
+            Err(
+                err @ (rusqlite::Error::FromSqlConversionFailure(..)
+                | rusqlite::Error::InvalidColumnType(..)
+                | rusqlite::Error::Utf8Error(..)),
+            ) => {
+                let rowid: i64 = row.get(0)?;
+                tracing::warn!(rowid, ?err, "skipping unreadable row");
+                skipped += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok((row_limit.apply(items)?, skipped))
+}
+
+fn get_item_db(conn: &Connection, key: String) -> anyhow::Result<Option<Item>> {
+    let mut stmt = conn.prepare("SELECT value FROM items WHERE key = ?1")?;
+    let result = stmt
+        .query_row([key.clone()], |row| row.get::<_, String>(0))
+        .optional()?;
+
+    Ok(result.map(|value| Item { key, value }))
+}
+
+fn get_item_bin_key_db(conn: &Connection, key: Vec<u8>) -> anyhow::Result<Option<String>> {
+    let mut stmt = conn.prepare("SELECT value FROM bin_items WHERE key = ?1")?;
+    let value = stmt.query_row([key], |row| row.get(0)).optional()?;
+    Ok(value)
+}
+
+fn exists_db(conn: &Connection, key: String) -> anyhow::Result<bool> {
+    let mut stmt = conn.prepare("SELECT 1 FROM items WHERE key = ?1")?;
+    Ok(stmt.exists([key])?)
+}
+
+// Adds the buffered counts to read_counts and empties the buffer. If that
+// fails the counts are kept for the next try, unless the buffer is already
+// full: then they're dropped, so a persistent failure can't make it grow
+// without bound.
+fn write_read_counts_db(
+    conn: &Connection,
+    read_counts: &mut HashMap<String, u64>,
+) -> anyhow::Result<()> {
+    if read_counts.is_empty() {
+        return Ok(());
+    }
+    let result = try_write_read_counts_db(conn, read_counts);
+    if result.is_ok() || read_counts.len() >= READ_COUNT_BATCH {
+        read_counts.clear();
+    }
+    result
+}
+
+fn try_write_read_counts_db(
+    conn: &Connection,
+    read_counts: &HashMap<String, u64>,
+) -> anyhow::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO read_counts (key, count) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET count = count + excluded.count",
+        )?;
+        for (key, count) in read_counts {
+            stmt.execute(params![key, count]).map_err(write_error)?;
+        }
+    }
+    tx.commit().map_err(write_error)?;
+    Ok(())
+}
+
+// Flushes read counts between requests, where the `query_only` switch that
+// requests get hasn't happened, so it turns `query_only` off itself.
+fn flush_read_counts(
+    conn: &Connection,
+    read_counts: &mut HashMap<String, u64>,
+    query_only: &mut bool,
+) {
+    if *query_only {
+        match conn.pragma_update(None, "query_only", false) {
+            Ok(()) => *query_only = false,
+            Err(err) => {
+                tracing::error!(?err, "failed to switch query_only");
+                return;
+            }
+        }
+    }
+    if let Err(err) = write_read_counts_db(conn, read_counts) {
+        tracing::error!(?err, "failed to write read counts");
+    }
+}
+
+fn get_read_count_db(conn: &Connection, key: String) -> anyhow::Result<u64> {
+    let count = conn
+        .query_row(
+            "SELECT count FROM read_counts WHERE key = ?1",
+            [key],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(count.unwrap_or(0))
+}
+
+fn get_page_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+    page: u32,
+    page_size: u32,
+    with_total: bool,
+) -> anyhow::Result<Page> {
+    let page_size = row_limit.page_size(page_size)?;
+    let tx = conn.unchecked_transaction()?;
+    let total = if with_total {
+        Some(tx.query_row("SELECT count(*) FROM items", [], |row| row.get(0))?)
+    } else {
+        None
+    };
+
+    let mut stmt = tx.prepare("SELECT key, value FROM items ORDER BY key LIMIT ?1 OFFSET ?2")?;
+    let items = stmt
+        .query_map(
+            params![page_size, i64::from(page) * i64::from(page_size)],
+            |row| {
+                Ok(Item {
+                    key: row.get(0)?,
+                    value: row.get(1)?,
+                })
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    drop(stmt);
+    tx.commit()?;
+
+    Ok(Page { items, total })
+}
+
+fn count_db(conn: &Connection, estimate: bool) -> anyhow::Result<u64> {
+    if estimate {
+        let has_meta: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'bgdb_meta')",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_meta {
+            let count = conn
+                .query_row(
+                    "SELECT value FROM bgdb_meta WHERE name = 'item_count'",
+                    [],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(count) = count {
+                return Ok(count);
+            }
+        }
+    }
+    let count = conn.query_row("SELECT count(*) FROM items", [], |row| row.get(0))?;
+    Ok(count)
+}
+
+fn get_by_index_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+    name: String,
+    value: serde_json::Value,
+) -> anyhow::Result<Vec<Item>> {
+    if check_index_name(&name).is_err() || !has_index_column(conn, &name)? {
+        bail!(UnknownIndex { name });
+    }
+    // The types SQLite's JSON functions produce for each kind of JSON value.
+    let value = match value {
+        serde_json::Value::Null => rusqlite::types::Value::Null,
+        serde_json::Value::Bool(b) => rusqlite::types::Value::Integer(b.into()),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(n) => rusqlite::types::Value::Integer(n),
+            None => rusqlite::types::Value::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(s) => rusqlite::types::Value::Text(s),
+        other => rusqlite::types::Value::Text(other.to_string()),
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT key, value FROM items WHERE idx_{name} = ?1 ORDER BY key LIMIT ?2"
+    ))?;
+    let items = stmt
+        .query_map(params![value, row_limit.sql()], |row| {
+            Ok(Item {
+                key: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    row_limit.apply(items)
+}
+
+fn get_by_tag_db(conn: &Connection, row_limit: RowLimit, tag: String) -> anyhow::Result<Vec<Item>> {
+    let mut stmt = conn.prepare(
+        "SELECT items.key, items.value FROM tags JOIN items ON items.key = tags.key
+         WHERE tags.tag = ?1 ORDER BY items.key LIMIT ?2",
+    )?;
+    let items = stmt
+        .query_map(params![tag, row_limit.sql()], |row| {
+            Ok(Item {
+                key: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    row_limit.apply(items)
+}
+
+fn get_tags_db(conn: &Connection, key: String) -> anyhow::Result<Option<Vec<String>>> {
+    let tx = conn.unchecked_transaction()?;
+    if !exists_db(&tx, key.clone())? {
+        return Ok(None);
+    }
+    let mut stmt = tx.prepare("SELECT tag FROM tags WHERE key = ?1 ORDER BY tag")?;
+    let tags = stmt
+        .query_map([key], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    drop(stmt);
+    tx.commit()?;
+    Ok(Some(tags))
+}
+
+fn add_tag_db(conn: &Connection, key: String, tag: String) -> anyhow::Result<bool> {
+    let tx = conn.unchecked_transaction()?;
+    if !exists_db(&tx, key.clone())? {
+        return Ok(false);
+    }
+    tx.execute(
+        "INSERT INTO tags (key, tag) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+        params![key, tag],
+    )
+    .map_err(write_error)?;
+    tx.commit().map_err(write_error)?;
+    Ok(true)
+}
+
+fn remove_tag_db(conn: &Connection, key: String, tag: String) -> anyhow::Result<bool> {
+    let removed = conn
+        .execute(
+            "DELETE FROM tags WHERE key = ?1 AND tag = ?2",
+            params![key, tag],
+        )
+        .map_err(write_error)?;
+    Ok(removed > 0)
+}
 
-use anyhow::{bail, Context};
-use rusqlite::{params, Connection, OptionalExtension};
-use tokio::sync::{mpsc, oneshot};
+fn query_sql(
+    row_limit: RowLimit,
+    filter: &Filter,
+) -> anyhow::Result<(String, Vec<rusqlite::types::Value>)> {
+    let mut sql = String::from("SELECT key, value FROM items WHERE ");
+    let mut params = Vec::new();
+    filter.to_sql(&mut sql, &mut params)?;
+    sql.push_str(" ORDER BY key LIMIT ?");
+    params.push(row_limit.sql().into());
+    Ok((sql, params))
+}
 
-use crate::Item;
+fn aggregate_db(conn: &Connection, prefix: String, op: Aggregate) -> anyhow::Result<Option<f64>> {
+    let mut sql = format!("SELECT {} FROM items WHERE ", op.as_sql());
+    let mut params = Vec::new();
+    Filter::PrefixEq(prefix).to_sql(&mut sql, &mut params)?;
+    let value = conn.query_row(&sql, params_from_iter(params), |row| row.get(0))?;
+    Ok(value)
+}
 
-pub fn open(path: PathBuf) -> anyhow::Result<Connection> {
-    let conn = Connection::open(path)?;
-    // Ensure the "items" table exists
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS items (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
-        [],
-    )
-    .context("Failed to create table")?;
-    Ok(conn)
+fn query_db(conn: &Connection, row_limit: RowLimit, filter: &Filter) -> anyhow::Result<Vec<Item>> {
+    let (sql, params) = query_sql(row_limit, filter)?;
+    let mut stmt = conn.prepare(&sql)?;
+    let items = stmt
+        .query_map(params_from_iter(params), |row| {
+            Ok(Item {
+                key: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    row_limit.apply(items)
 }
-pub fn spawn(conn: Connection) -> DatabaseClient {
-    let (db_tx, db_rx) = mpsc::channel::<DbRequest>(32);
-    std::thread::spawn(|| {
-        tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap()
-            .block_on(database_thread(conn, db_rx))
-    });
-    DatabaseClient { db_tx }
+
+// Without sizes this only needs the key index, never the table itself.
+fn get_key_sizes_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+    with_size: bool,
+) -> anyhow::Result<Vec<KeySize>> {
+    let size = if with_size {
+        "length(CAST(value AS BLOB))"
+    } else {
+        "0"
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT key, {size} FROM items ORDER BY key LIMIT ?1"
+    ))?;
+    let sizes = stmt
+        .query_map([row_limit.sql()], |row| {
+            Ok(KeySize {
+                key: row.get(0)?,
+                size: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    row_limit.apply(sizes)
 }
 
-#[derive(Clone)]
-pub struct DatabaseClient {
-    db_tx: mpsc::Sender<DbRequest>,
+// Computed from scratch every time. Each caller picks its own `prefix_len`,
+// so there's no single set of buckets to keep up to date, and keeping even one
+// current would mean hooking every write path, including writes made by other
+// connections to the same file.
+fn bucket_hashes_db(conn: &Connection, prefix_len: usize) -> anyhow::Result<HashMap<String, u64>> {
+    let mut hasher = BucketHasher::new(prefix_len);
+    let mut stmt = conn.prepare("SELECT key, value FROM items")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        hasher.add(row.get_ref(0)?.as_str()?, row.get_ref(1)?.as_str()?);
+    }
+    Ok(hasher.finish())
 }
 
-enum DbRequest {
-    GetAll {
-        respond_to: oneshot::Sender<anyhow::Result<Vec<Item>>>,
-    },
-    GetItem {
-        key: String,
-        respond_to: oneshot::Sender<anyhow::Result<Option<Item>>>,
-    },
-    PutItem {
-        item: Item,
-        respond_to: oneshot::Sender<anyhow::Result<()>>,
-    },
-    Shutdown {
-        respond_to: oneshot::Sender<anyhow::Result<()>>,
-    },
+fn top_by_size_db(conn: &Connection, row_limit: RowLimit, n: u32) -> anyhow::Result<Vec<KeySize>> {
+    let n = row_limit.page_size(n)?;
+    // length() of TEXT counts characters; the cast makes it count bytes,
+    // which is what takes up space.
+    let mut stmt = conn.prepare(
+        "SELECT key, length(CAST(value AS BLOB)) AS size FROM items ORDER BY size DESC LIMIT ?1",
+    )?;
+    let sizes = stmt
+        .query_map([n], |row| {
+            Ok(KeySize {
+                key: row.get(0)?,
+                size: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(sizes)
 }
-impl std::fmt::Debug for DbRequest {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::GetAll { .. } => f.debug_struct("GetAll").finish(),
-            Self::GetItem { key, .. } => f.debug_struct("GetItem").field("key", key).finish(),
-            Self::PutItem { item, .. } => f.debug_struct("PutItem").field("item", item).finish(),
-            Self::Shutdown { .. } => f.debug_struct("Shutdown").finish(),
+
+fn list_prefixes_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+    prefix: String,
+    delimiter: String,
+) -> anyhow::Result<Vec<String>> {
+    if delimiter.is_empty() {
+        bail!("the delimiter can't be empty");
+    }
+    // The filter's plain `?`s number on from ?3, so its parameters follow
+    // the prefix and delimiter, and the limit comes last.
+    let mut sql = "SELECT DISTINCT substr(key, 1, length(?1) + instr(substr(key, length(?1) + 1), ?2) + length(?2) - 1) AS dir
+         FROM items WHERE "
+        .to_owned();
+    let mut params = vec![
+        rusqlite::types::Value::Text(prefix.clone()),
+        rusqlite::types::Value::Text(delimiter),
+    ];
+    Filter::PrefixEq(prefix).to_sql(&mut sql, &mut params)?;
+    sql.push_str(" AND instr(substr(key, length(?1) + 1), ?2) > 0 ORDER BY dir LIMIT ?");
+    params.push(rusqlite::types::Value::Integer(row_limit.sql()));
+    let mut stmt = conn.prepare(&sql)?;
+    let prefixes = stmt
+        .query_map(params_from_iter(params), |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    row_limit.apply(prefixes)
+}
+
+fn find_duplicates_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+    min_count: u32,
+) -> anyhow::Result<Vec<Duplicate>> {
+    // The keys come back as a JSON array rather than GROUP_CONCAT's list, so
+    // that a key with a comma in it survives.
+    let mut stmt = conn.prepare(
+        "SELECT value, count(*) AS c, json_group_array(key) FROM
+           (SELECT key, value FROM items ORDER BY key)
+         GROUP BY value HAVING c >= ?1
+         ORDER BY c DESC, value
+         LIMIT ?2",
+    )?;
+    let rows = stmt
+        .query_map(params![min_count.max(2), row_limit.sql()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let duplicates = rows
+        .into_iter()
+        .map(|(value, count, keys)| {
+            Ok(Duplicate {
+                value,
+                count,
+                keys: serde_json::from_str(&keys)?,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    row_limit.apply(duplicates)
+}
+
+fn get_all_sorted_by_value_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+    descending: bool,
+    limit: u32,
+) -> anyhow::Result<Vec<Item>> {
+    let limit = row_limit.page_size(limit.min(MAX_SORTED_BY_VALUE))?;
+    // A value that is a JSON number sorts as that number; SQLite puts numbers
+    // before text, so everything else follows, compared as text.
+    let sql = if descending {
+        "SELECT key, value FROM items ORDER BY \
+         CASE WHEN json_valid(value) AND json_type(value) IN ('integer', 'real') \
+         THEN json_extract(value, '$') ELSE value END DESC, value DESC, key DESC LIMIT ?1"
+    } else {
+        "SELECT key, value FROM items ORDER BY \
+         CASE WHEN json_valid(value) AND json_type(value) IN ('integer', 'real') \
+         THEN json_extract(value, '$') ELSE value END, value, key LIMIT ?1"
+    };
+    let mut stmt = conn.prepare(sql)?;
+    let items = stmt
+        .query_map([limit], |row| {
+            Ok(Item {
+                key: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(items)
+}
+
+fn get_window_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+    start_key: String,
+    count: u32,
+    direction: Direction,
+) -> anyhow::Result<Vec<Item>> {
+    let count = row_limit.page_size(count)?;
+    let sql = match direction {
+        Direction::Forward => "SELECT key, value FROM items WHERE key >= ?1 ORDER BY key LIMIT ?2",
+        Direction::Backward => {
+            "SELECT key, value FROM items WHERE key < ?1 ORDER BY key DESC LIMIT ?2"
         }
+    };
+    let mut stmt = conn.prepare(sql)?;
+    let mut items = stmt
+        .query_map(params![start_key, count], |row| {
+            Ok(Item {
+                key: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if direction == Direction::Backward {
+        items.reverse();
     }
+    Ok(items)
 }
 
-impl DatabaseClient {
-    pub async fn get_all_items(&self) -> anyhow::Result<Vec<Item>> {
-        let (respond_to, response) = oneshot::channel();
+fn for_each_item_db(conn: &Connection, f: &mut dyn FnMut(Item)) -> anyhow::Result<usize> {
+    let mut stmt = conn.prepare("SELECT key, value FROM items ORDER BY key")?;
+    let mut rows = stmt.query([])?;
 
-        self.db_tx.send(DbRequest::GetAll { respond_to }).await?;
+    let mut visited = 0;
+    while let Some(row) = rows.next()? {
+        f(Item {
+            key: row.get(0)?,
+            value: row.get(1)?,
+        });
+        visited += 1;
+    }
+    Ok(visited)
+}
 
-        response.await?
+// Schema names can't be bound as parameters in a SELECT, so they have to be
+// spliced into the SQL. Only ever splice in names SQLite itself reports.
+fn attached_schema(conn: &Connection, alias: String) -> anyhow::Result<String> {
+    let known = conn
+        .query_row(
+            "SELECT 1 FROM pragma_database_list WHERE name = ?1",
+            [&alias],
+            |_| Ok(()),
+        )
+        .optional()?;
+    match known {
+        Some(()) => Ok(format!("\"{}\"", alias.replace('"', "\"\""))),
+        None => bail!(UnknownDatabase { alias }),
     }
+}
 
-    pub async fn get_item(&self, key: String) -> anyhow::Result<Option<Item>> {
-        let (respond_to, response) = oneshot::channel();
+fn get_all_attached_items_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+    alias: String,
+) -> anyhow::Result<Vec<Item>> {
+    let schema = attached_schema(conn, alias)?;
+    let mut stmt = conn.prepare(&format!("SELECT key, value FROM {schema}.items LIMIT ?1"))?;
+    let items = stmt
+        .query_map([row_limit.sql()], |row| {
+            Ok(Item {
+                key: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    row_limit.apply(items)
+}
 
-        self.db_tx
-            .send(DbRequest::GetItem { key, respond_to })
-            .await?;
+fn get_attached_item_db(
+    conn: &Connection,
+    alias: String,
+    key: String,
+) -> anyhow::Result<Option<Item>> {
+    let schema = attached_schema(conn, alias)?;
+    let mut stmt = conn.prepare(&format!("SELECT value FROM {schema}.items WHERE key = ?1"))?;
+    let result = stmt
+        .query_row([&key], |row| row.get::<_, String>(0))
+        .optional()?;
 
-        response.await?
+    Ok(result.map(|value| Item { key, value }))
+}
+
+// json_extract on a value that isn't JSON is an error for the whole query, so
+// check validity first and only project the values that can be projected.
+// json_quote turns whatever json_extract found (including nothing, i.e. NULL)
+// back into JSON text.
+const PROJECTED_VALUE: &str =
+    "json_valid(value), CASE WHEN json_valid(value) THEN json_quote(json_extract(value, :path)) END";
+
+fn get_all_items_projected_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+    path: String,
+) -> anyhow::Result<Vec<Item>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT key, {PROJECTED_VALUE} FROM items LIMIT :limit"
+    ))?;
+    let mut rows = stmt.query(named_params! { ":path": path, ":limit": row_limit.sql() })?;
+
+    let mut items = Vec::new();
+    while let Some(row) = rows.next()? {
+        let key: String = row.get(0)?;
+        if !row.get::<_, bool>(1)? {
+            bail!(NotJson { key });
+        }
+        items.push(Item {
+            key,
+            value: row.get(2)?,
+        });
     }
+    row_limit.apply(items)
+}
 
-    pub async fn put_item(&self, item: Item) -> anyhow::Result<()> {
-        let (respond_to, response) = oneshot::channel();
+fn get_value_range_db(
+    conn: &Connection,
+    key: String,
+    range: ByteRange,
+) -> anyhow::Result<Option<ValueSlice>> {
+    // The length and the slice have to come from the same version of the
+    // value.
+    let tx = conn.unchecked_transaction()?;
+    let total: Option<u64> = tx
+        .query_row(
+            "SELECT length(CAST(value AS BLOB)) FROM items WHERE key = ?1",
+            [&key],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let Some(total) = total else {
+        return Ok(None);
+    };
+    let Some((start, end)) = range.resolve(total) else {
+        bail!(RangeNotSatisfiable { total });
+    };
+    let bytes = tx.query_row(
+        "SELECT substr(CAST(value AS BLOB), ?2, ?3) FROM items WHERE key = ?1",
+        params![key, start + 1, end - start + 1],
+        |row| row.get(0),
+    )?;
+    Ok(Some(ValueSlice {
+        bytes,
+        start,
+        end,
+        total,
+    }))
+}
 
-        self.db_tx
-            .send(DbRequest::PutItem { item, respond_to })
-            .await?;
+fn get_item_projected_db(
+    conn: &Connection,
+    key: String,
+    path: String,
+) -> anyhow::Result<Option<Item>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {PROJECTED_VALUE} FROM items WHERE key = :key"
+    ))?;
+    let result = stmt
+        .query_row(named_params! { ":key": key, ":path": path }, |row| {
+            Ok((row.get::<_, bool>(0)?, row.get::<_, Option<String>>(1)?))
+        })
+        .optional()?;
 
-        response.await?
+    match result {
+        None => Ok(None),
+        Some((true, Some(value))) => Ok(Some(Item { key, value })),
+        Some(_) => bail!(NotJson { key }),
     }
+}
 
-    pub async fn shutdown(&self) -> anyhow::Result<()> {
-        let (respond_to, response) = oneshot::channel();
+// Returns the values found for `keys`, keyed by the key exactly as requested.
+// That differs from the stored key when the key column is case-insensitive,
+// which is why this joins against the requested keys rather than using
+// `key IN (...)`. Putting items.key on the left of the comparison makes it use
+// that column's collation (and so its index).
+fn get_values_db(conn: &Connection, keys: &[&str]) -> anyhow::Result<HashMap<String, String>> {
+    if keys.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let placeholders = vec!["(?)"; keys.len()].join(", ");
+    let mut stmt = conn.prepare(&format!(
+        "WITH wanted(key) AS (VALUES {placeholders}) \
+         SELECT wanted.key, items.value FROM wanted JOIN items ON items.key = wanted.key"
+    ))?;
+    let values = stmt
+        .query_map(params_from_iter(keys), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(values)
+}
 
-        self.db_tx.send(DbRequest::Shutdown { respond_to }).await?;
+fn get_items_db(
+    conn: &Connection,
+    bloom: &Option<BloomFilter>,
+    keys: Vec<String>,
+) -> anyhow::Result<Vec<Option<Item>>> {
+    let mut wanted: Vec<&str> = keys
+        .iter()
+        .map(String::as_str)
+        .filter(|key| bloom_might_contain(bloom, key))
+        .collect();
+    wanted.sort_unstable();
+    wanted.dedup();
 
-        response.await?
+    // Keep each query well under SQLite's limit on bound parameters, and run
+    // them all in one transaction so that, chunked or not, they see the same
+    // snapshot.
+    let tx = conn.unchecked_transaction()?;
+    let mut values = HashMap::new();
+    for chunk in wanted.chunks(MAX_COALESCED_REQUESTS) {
+        values.extend(get_values_db(&tx, chunk)?);
     }
+    tx.commit()?;
+    Ok(keys
+        .into_iter()
+        .map(|key| {
+            let value = values.get(&key)?.clone();
+            Some(Item { key, value })
+        })
+        .collect())
 }
 
-// This is an abomination: an async function that does a ton of blocking I/O.
-// This should only be run in a dedicated runtime.
-async fn database_thread(conn: Connection, mut db_rx: mpsc::Receiver<DbRequest>) {
-    // Listen for database requests
-    while let Some(request) = db_rx.recv().await {
-        tracing::debug!(?request, "recv");
-        match request {
-            DbRequest::GetAll { respond_to } => {
-                let result = get_all_items_db(&conn);
-                let _ = respond_to.send(result);
+fn get_items_coalesced_db(
+    conn: &Connection,
+    health: &mut Health,
+    bloom: &Option<BloomFilter>,
+    batch: Vec<(
+        String,
+        oneshot::Sender<anyhow::Result<(Option<Item>, bool)>>,
+    )>,
+) {
+    let mut keys: Vec<&str> = batch
+        .iter()
+        .map(|(key, _)| key.as_str())
+        .filter(|key| bloom_might_contain(bloom, key))
+        .collect();
+    keys.sort_unstable();
+    keys.dedup();
+    tracing::debug!(requests = batch.len(), keys = keys.len(), "coalesced read");
+
+    let result = get_values_db(conn, &keys);
+    // One query, so it counts once towards `health`, however many it answers.
+    health.record(&result);
+    match result {
+        Ok(values) => {
+            for (key, respond_to) in batch {
+                let cached = !bloom_might_contain(bloom, &key);
+                let item = values.get(&key).map(|value| Item {
+                    value: value.clone(),
+                    key,
+                });
+                let _ = respond_to.send(Ok((item, cached)));
             }
-            DbRequest::GetItem { key, respond_to } => {
-                let result = get_item_db(&conn, key);
-                let _ = respond_to.send(result);
+        }
+        Err(err) => {
+            for (_, respond_to) in batch {
+                let _ = respond_to.send(Err(copy_error(&err)));
             }
-            DbRequest::PutItem { item, respond_to } => {
-                let result = put_item_db(&conn, item);
-                let _ = respond_to.send(result);
+        }
+    }
+}
+
+// anyhow::Error isn't Clone, so when one failure has to be reported to several
+// waiters, each gets a copy of the message, plus any marker they'd match on.
+fn copy_error(err: &anyhow::Error) -> anyhow::Error {
+    let copy = anyhow!("{err:#}");
+    if err.is::<StorageFull>() {
+        copy.context(StorageFull)
+    } else if err.is::<ShuttingDown>() {
+        copy.context(ShuttingDown)
+    } else {
+        copy
+    }
+}
+
+fn put_item_db(conn: &Connection, item: Item) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT INTO items (key, value) VALUES (?1, ?2) \
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![item.key, item.value],
+    )
+    .map_err(write_error)?;
+    Ok(())
+}
+
+fn put_item_bin_key_db(conn: &Connection, key: Vec<u8>, value: String) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT INTO bin_items (key, value) VALUES (?1, ?2) \
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![key, value],
+    )
+    .map_err(write_error)?;
+    Ok(())
+}
+
+// Raises `synchronous` to FULL for just this write, if it's lower. At FULL
+// the commit is fsynced (the WAL in WAL mode, the journal and database
+// otherwise) before it returns.
+fn put_item_durable_db(conn: &Connection, item: Item) -> anyhow::Result<()> {
+    durably(conn, |conn| put_item_db(conn, item))
+}
+
+// Runs `write` with `synchronous = FULL`, then puts the setting back.
+fn durably<T>(
+    conn: &Connection,
+    write: impl FnOnce(&Connection) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let current =
+        Synchronous::from_pragma(conn.query_row("PRAGMA synchronous", [], |row| row.get(0))?);
+    if current >= Synchronous::Full {
+        return write(conn);
+    }
+    conn.pragma_update(None, "synchronous", Synchronous::Full.as_pragma())?;
+    let result = write(conn);
+    // The write has happened (or not) by now, so its result is the answer
+    // either way; failing to put the setting back only costs some speed.
+    if let Err(err) = conn.pragma_update(None, "synchronous", current.as_pragma()) {
+        tracing::warn!(?err, "failed to restore synchronous after a durable write");
+    }
+    result
+}
+
+// The WHERE on the upsert turns an identical write into a no-op, which SQLite
+// then doesn't count as a change.
+fn put_item_if_changed_db(conn: &Connection, item: Item) -> anyhow::Result<bool> {
+    let changed = conn
+        .execute(
+            "INSERT INTO items (key, value) VALUES (?1, ?2) \
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value \
+             WHERE items.value IS NOT excluded.value",
+            params![item.key, item.value],
+        )
+        .map_err(write_error)?;
+    Ok(changed > 0)
+}
+
+// RETURNING gives back the row as SQLite stored it. With `if_changed`, an
+// identical write returns no row, but then the stored row is the one we have.
+fn put_item_returning_db(
+    conn: &Connection,
+    item: Item,
+    if_changed: bool,
+) -> anyhow::Result<(Item, bool)> {
+    let sql = if if_changed {
+        "INSERT INTO items (key, value) VALUES (?1, ?2) \
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value \
+         WHERE items.value IS NOT excluded.value \
+         RETURNING key, value"
+    } else {
+        "INSERT INTO items (key, value) VALUES (?1, ?2) \
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value \
+         RETURNING key, value"
+    };
+    let stored = conn
+        .query_row(sql, params![item.key, item.value], |row| {
+            Ok(Item {
+                key: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })
+        .optional()
+        .map_err(write_error)?;
+    Ok(match stored {
+        Some(stored) => (stored, true),
+        None => (item, false),
+    })
+}
+
+fn put_items_db(conn: &Connection, items: Vec<Item>) -> anyhow::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for item in items {
+        put_item_db(&tx, item)?;
+    }
+    tx.commit().map_err(write_error)?;
+    Ok(())
+}
+
+fn put_items_if_absent_db(conn: &Connection, items: Vec<Item>) -> anyhow::Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let mut written = 0;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO items (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO NOTHING",
+        )?;
+        for item in items {
+            written += stmt
+                .execute(params![item.key, item.value])
+                .map_err(write_error)?;
+        }
+    }
+    tx.commit().map_err(write_error)?;
+    Ok(written)
+}
+
+fn apply_db(conn: &Connection, ops: Vec<Op>) -> anyhow::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for (index, op) in ops.into_iter().enumerate() {
+        match op {
+            Op::Put { key, value } => put_item_db(&tx, Item { key, value })?,
+            Op::Delete { key } => {
+                tx.execute("DELETE FROM items WHERE key = ?1", [key])
+                    .map_err(write_error)?;
             }
-            DbRequest::Shutdown { respond_to } => {
-                let _ = respond_to.send(shutdown(conn));
-                break;
+            Op::CheckEquals { key, value } => {
+                let current: Option<String> = tx
+                    .query_row("SELECT value FROM items WHERE key = ?1", [&key], |row| {
+                        row.get(0)
+                    })
+                    .optional()?;
+                if current.as_deref() != Some(value.as_str()) {
+                    // Dropping the transaction rolls it back.
+                    bail!(CheckFailed { index, key });
+                }
             }
         }
     }
+    tx.commit().map_err(write_error)?;
+    Ok(())
 }
 
-// Database operation functions
-fn get_all_items_db(conn: &Connection) -> anyhow::Result<Vec<Item>> {
-    let mut stmt = conn.prepare("SELECT key, value FROM items")?;
-    let item_iter = stmt.query_map([], |row| {
-        Ok(Item {
-            key: row.get(0)?,
-            value: row.get(1)?,
-        })
-    })?;
+fn put_items_best_effort_db(
+    conn: &Connection,
+    items: Vec<Item>,
+) -> anyhow::Result<Vec<Result<(), String>>> {
+    let results = put_items_each_db(conn, items)?;
+    Ok(results
+        .into_iter()
+        .map(|result| result.map_err(|err| format!("{err:#}")))
+        .collect())
+}
 
-    let mut items = Vec::new();
-    for item in item_iter {
-        items.push(item?);
+// Writes each item under its own savepoint in one transaction, keeping every
+// item's error as it was raised so a full disk still reads as `StorageFull`.
+fn put_items_each_db(
+    conn: &Connection,
+    items: Vec<Item>,
+) -> anyhow::Result<Vec<anyhow::Result<()>>> {
+    let mut tx = conn.unchecked_transaction()?;
+    let mut results = Vec::with_capacity(items.len());
+    for item in items {
+        // Dropping the savepoint without committing it rolls back just this item.
+        let savepoint = tx.savepoint()?;
+        let result = put_item_db(&savepoint, item);
+        if result.is_ok() {
+            savepoint.commit()?;
+        }
+        results.push(result);
     }
-    Ok(items)
+    tx.commit().map_err(write_error)?;
+    Ok(results)
 }
 
-fn get_item_db(conn: &Connection, key: String) -> anyhow::Result<Option<Item>> {
-    let mut stmt = conn.prepare("SELECT value FROM items WHERE key = ?1")?;
+fn put_items_coalesced_db(
+    conn: &Connection,
+    health: &mut Health,
+    batch: Vec<(Item, oneshot::Sender<anyhow::Result<()>>)>,
+) {
+    tracing::debug!(requests = batch.len(), "coalesced write");
+    let (items, waiters): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+    match put_items_each_db(conn, items) {
+        Ok(results) => {
+            for (result, respond_to) in results.into_iter().zip(waiters) {
+                reply(health, respond_to, result);
+            }
+        }
+        Err(err) => {
+            for respond_to in waiters {
+                let _ = respond_to.send(Err(copy_error(&err)));
+            }
+            // The copies lose the underlying error, so `health` gets the
+            // original, once for the one transaction.
+            health.record::<()>(&Err(err));
+        }
+    }
+}
+
+// Tags errors that mean the disk is full (or failing) with `StorageFull`, so
+// callers can tell them apart from everything else.
+fn write_error(err: rusqlite::Error) -> anyhow::Error {
+    match err.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::DiskFull | rusqlite::ErrorCode::SystemIoFailure) => {
+            tracing::warn!(
+                ?err,
+                "DATABASE STORAGE IS FULL: rejecting writes, reads still served"
+            );
+            anyhow::Error::new(err).context(StorageFull)
+        }
+        _ => err.into(),
+    }
+}
+
+// Both pops are a single DELETE ... RETURNING, so the read and the delete
+// can't be separated.
+fn pop_db(conn: &Connection, key: String) -> anyhow::Result<Option<Item>> {
+    let mut stmt = conn.prepare("DELETE FROM items WHERE key = ?1 RETURNING value")?;
     let result = stmt
-        .query_row([key.clone()], |row| row.get::<_, String>(0))
-        .optional()?;
+        .query_row([&key], |row| row.get::<_, String>(0))
+        .optional()
+        .map_err(write_error)?;
 
     Ok(result.map(|value| Item { key, value }))
 }
 
-fn put_item_db(conn: &Connection, item: Item) -> anyhow::Result<()> {
-    conn.execute(
+fn archive_db(conn: &Connection, from: String, key: String) -> anyhow::Result<Option<Item>> {
+    let tx = conn.unchecked_transaction()?;
+    let value = tx
+        .query_row(
+            "DELETE FROM items WHERE key = ?1 RETURNING value",
+            [&from],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()
+        .map_err(write_error)?;
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    tx.execute(
+        "INSERT INTO items (key, value) VALUES (?1, ?2)",
+        params![key, value],
+    )
+    .map_err(write_error)
+    .with_context(|| format!("failed to archive {from:?} as {key:?}"))?;
+    tx.commit().map_err(write_error)?;
+    Ok(Some(Item { key, value }))
+}
+
+fn delete_range_db(conn: &Connection, start: String, end: String) -> anyhow::Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let deleted = tx
+        .execute(
+            "DELETE FROM items WHERE key >= ?1 AND key < ?2",
+            params![start, end],
+        )
+        .map_err(write_error)?;
+    tx.commit().map_err(write_error)?;
+    Ok(deleted)
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+// When a lease taken at `now` for `ttl` runs out, clamped rather than wrapping.
+fn lease_expiry(now: i64, ttl: Duration) -> i64 {
+    now.saturating_add(i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX))
+}
+
+fn acquire_leadership_db(
+    conn: &Connection,
+    key: String,
+    candidate_id: String,
+    ttl: Duration,
+) -> anyhow::Result<Leadership> {
+    let now = now_ms();
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO leaders (key, leader, expires_at) VALUES (?1, ?2, ?3) \
+         ON CONFLICT(key) DO UPDATE SET leader = excluded.leader, expires_at = excluded.expires_at \
+         WHERE leaders.expires_at <= ?4 OR leaders.leader = excluded.leader",
+        params![key, candidate_id, lease_expiry(now, ttl), now],
+    )
+    .map_err(write_error)?;
+    let leadership = leadership_db(&tx, &key, &candidate_id, now)?;
+    tx.commit().map_err(write_error)?;
+    Ok(leadership)
+}
+
+fn renew_leadership_db(
+    conn: &Connection,
+    key: String,
+    candidate_id: String,
+    ttl: Duration,
+) -> anyhow::Result<Leadership> {
+    let now = now_ms();
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE leaders SET expires_at = ?3 WHERE key = ?1 AND leader = ?2 AND expires_at > ?4",
+        params![key, candidate_id, lease_expiry(now, ttl), now],
+    )
+    .map_err(write_error)?;
+    let leadership = leadership_db(&tx, &key, &candidate_id, now)?;
+    tx.commit().map_err(write_error)?;
+    Ok(leadership)
+}
+
+// Who holds the lease on `key` as of `now`, from `candidate_id`'s point of
+// view.
+fn leadership_db(
+    conn: &Connection,
+    key: &str,
+    candidate_id: &str,
+    now: i64,
+) -> anyhow::Result<Leadership> {
+    let lease = conn
+        .query_row(
+            "SELECT leader, expires_at FROM leaders WHERE key = ?1 AND expires_at > ?2",
+            params![key, now],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+        )
+        .optional()?;
+    Ok(Leadership {
+        held: lease
+            .as_ref()
+            .is_some_and(|(leader, _)| leader == candidate_id),
+        expires_at_ms: lease.as_ref().map(|(_, expires_at)| *expires_at),
+        leader: lease.map(|(leader, _)| leader),
+    })
+}
+
+fn decrement_floor_db(
+    conn: &Connection,
+    key: String,
+    delta: i64,
+    floor: i64,
+) -> anyhow::Result<(i64, bool)> {
+    if delta < 0 {
+        bail!("can't decrement by a negative amount ({delta})");
+    }
+    let tx = conn.unchecked_transaction()?;
+    let current = tx
+        .query_row("SELECT value FROM items WHERE key = ?1", [&key], |row| {
+            row.get::<_, String>(0)
+        })
+        .optional()?;
+    let current = match current {
+        Some(value) => match value.trim().parse::<i64>() {
+            Ok(current) => current,
+            Err(_) => bail!(NotAnInteger { key }),
+        },
+        None => 0,
+    };
+    let wanted = current.saturating_sub(delta);
+    let floor_hit = wanted < floor;
+    let new = if floor_hit {
+        floor.min(current)
+    } else {
+        wanted
+    };
+    tx.execute(
         "INSERT INTO items (key, value) VALUES (?1, ?2) \
          ON CONFLICT(key) DO UPDATE SET value = excluded.value",
-        params![item.key, item.value],
+        params![key, new.to_string()],
+    )
+    .map_err(write_error)?;
+    tx.commit().map_err(write_error)?;
+    Ok((new, floor_hit))
+}
+
+fn pop_any_db(conn: &Connection) -> anyhow::Result<Option<Item>> {
+    let mut stmt = conn.prepare(
+        "DELETE FROM items WHERE key = (SELECT min(key) FROM items) RETURNING key, value",
+    )?;
+    let result = stmt
+        .query_row([], |row| {
+            Ok(Item {
+                key: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })
+        .optional()
+        .map_err(write_error)?;
+    Ok(result)
+}
+
+// Committed data lives in the database file and, in WAL mode, the WAL. With
+// synchronous below FULL (OFF, for checkpoints too) SQLite may not have
+// fsynced either, so do it here rather than relying on a checkpoint.
+fn flush_db(conn: &Connection) -> anyhow::Result<()> {
+    let path = match conn.path() {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        // In-memory and temporary databases have nothing to flush.
+        _ => return Ok(()),
+    };
+    let mut wal = path.clone().into_os_string();
+    wal.push("-wal");
+    for file in [path, PathBuf::from(wal)] {
+        match std::fs::File::open(&file) {
+            Ok(f) => f
+                .sync_all()
+                .with_context(|| format!("Failed to sync {}", file.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to open {}", file.display()))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn diagnostics_db(
+    conn: &Connection,
+    slow_requests: &VecDeque<SlowRequest>,
+) -> anyhow::Result<Diagnostics> {
+    let sqlite_version = conn.query_row("SELECT sqlite_version()", [], |row| row.get(0))?;
+    let schema_version = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    let mut pragmas = BTreeMap::new();
+    for name in [
+        "journal_mode",
+        "synchronous",
+        "page_size",
+        "page_count",
+        "freelist_count",
+        "cache_size",
+        "busy_timeout",
+        "wal_autocheckpoint",
+        "auto_vacuum",
+    ] {
+        let value = conn.query_row(&format!("PRAGMA {name}"), [], |row| {
+            Ok(match row.get::<_, rusqlite::types::Value>(0)? {
+                rusqlite::types::Value::Integer(n) => serde_json::Value::from(n),
+                rusqlite::types::Value::Text(s) => serde_json::Value::from(s),
+                _ => serde_json::Value::Null,
+            })
+        })?;
+        pragmas.insert(name.to_owned(), value);
+    }
+
+    let item_count = conn.query_row("SELECT count(*) FROM items", [], |row| row.get(0))?;
+    let size_bytes = conn.query_row(
+        "SELECT page_count * page_size FROM pragma_page_count, pragma_page_size",
+        [],
+        |row| row.get(0),
+    )?;
+    let mut stmt = conn.prepare("PRAGMA integrity_check(100)")?;
+    let integrity_check = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(Diagnostics {
+        sqlite_version,
+        schema_version,
+        pragmas,
+        item_count,
+        size_bytes,
+        integrity_check,
+        slow_requests: slow_requests.iter().cloned().collect(),
+    })
+}
+
+fn warmup_db(conn: &Connection, full: bool) -> anyhow::Result<()> {
+    let sql = if full {
+        // length() of a TEXT value has to read all of it, overflow pages included.
+        "SELECT count(*), sum(length(value)) FROM items"
+    } else {
+        "SELECT count(*), 0 FROM items"
+    };
+    let (count, bytes): (i64, Option<i64>) =
+        conn.query_row(sql, [], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    tracing::info!(count, ?bytes, full, "warmed up");
+    Ok(())
+}
+
+fn get_schema_db(conn: &Connection) -> anyhow::Result<Vec<SchemaObject>> {
+    // Indexes SQLite makes on its own (for the primary key) have no SQL.
+    let mut stmt = conn.prepare(
+        "SELECT type, name, sql FROM main.sqlite_master
+         WHERE tbl_name = 'items' AND sql IS NOT NULL
+         ORDER BY type != 'table', type, name",
     )?;
+    let objects = stmt
+        .query_map([], |row| {
+            Ok(SchemaObject {
+                kind: row.get(0)?,
+                name: row.get(1)?,
+                sql: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(objects)
+}
+
+// The SQL here has to be kept the same as in the functions it stands in for.
+fn explain_db(
+    conn: &Connection,
+    row_limit: RowLimit,
+    query: Explain,
+) -> anyhow::Result<Vec<PlanStep>> {
+    let (sql, params) = match query {
+        Explain::Scan => (
+            "SELECT key, value FROM items LIMIT ?1".to_owned(),
+            vec![row_limit.sql().into()],
+        ),
+        Explain::Query(filter) => query_sql(row_limit, &filter)?,
+        Explain::Range { start, end } => (
+            "DELETE FROM items WHERE key >= ?1 AND key < ?2".to_owned(),
+            vec![start.into(), end.into()],
+        ),
+    };
+    let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {sql}"))?;
+    let steps = stmt
+        .query_map(params_from_iter(params), |row| {
+            Ok(PlanStep {
+                id: row.get(0)?,
+                parent: row.get(1)?,
+                detail: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(steps)
+}
+
+fn maintain_db(conn: &Connection, incremental_vacuum: bool) -> anyhow::Result<()> {
+    // Outside WAL mode this is a no-op that reports (0, -1, -1).
+    let (busy, wal_pages): (bool, i64) =
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+    conn.execute_batch("PRAGMA optimize")?;
+    if incremental_vacuum {
+        conn.execute_batch("PRAGMA incremental_vacuum")?;
+    }
+    tracing::info!(busy, wal_pages, incremental_vacuum, "maintenance done");
     Ok(())
 }
 
@@ -172,3 +4435,109 @@ fn shutdown(conn: Connection) -> anyhow::Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conn_with(items: &[(&str, &str)]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE items (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+            [],
+        )
+        .unwrap();
+        for (key, value) in items {
+            conn.execute(
+                "INSERT INTO items (key, value) VALUES (?1, ?2)",
+                [key, value],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn truncate_value_keeps_short_values() {
+        let mut value = "héllo".to_owned();
+        assert!(!truncate_value(&mut value, 6));
+        assert_eq!(value, "héllo");
+        assert!(!truncate_value(&mut value, 100));
+        assert_eq!(value, "héllo");
+    }
+
+    #[test]
+    fn truncate_value_backs_off_to_a_char_boundary() {
+        // "é" takes bytes 1 and 2.
+        let mut value = "héllo".to_owned();
+        assert!(truncate_value(&mut value, 2));
+        assert_eq!(value, "h");
+
+        let mut value = "héllo".to_owned();
+        assert!(truncate_value(&mut value, 3));
+        assert_eq!(value, "hé");
+
+        let mut value = "€".to_owned();
+        assert!(truncate_value(&mut value, 2));
+        assert_eq!(value, "");
+
+        let mut value = "abc".to_owned();
+        assert!(truncate_value(&mut value, 0));
+        assert_eq!(value, "");
+    }
+
+    #[test]
+    fn lease_expiry_adds_the_ttl() {
+        assert_eq!(lease_expiry(1_000, Duration::from_millis(1)), 1_001);
+        assert_eq!(
+            lease_expiry(1_000, Duration::from_secs(24 * 60 * 60)),
+            1_000 + 86_400_000
+        );
+    }
+
+    #[test]
+    fn lease_expiry_saturates() {
+        assert_eq!(
+            lease_expiry(i64::MAX - 1, Duration::from_millis(10)),
+            i64::MAX
+        );
+        assert_eq!(lease_expiry(0, Duration::MAX), i64::MAX);
+        assert_eq!(lease_expiry(1, Duration::from_millis(u64::MAX)), i64::MAX);
+    }
+
+    #[test]
+    fn byte_range_resolves_within_the_value() {
+        assert_eq!(ByteRange::FromTo(0, 0).resolve(3), Some((0, 0)));
+        assert_eq!(ByteRange::FromTo(1, 100).resolve(3), Some((1, 2)));
+        assert_eq!(ByteRange::From(1).resolve(3), Some((1, 2)));
+        assert_eq!(ByteRange::Last(2).resolve(3), Some((1, 2)));
+        assert_eq!(ByteRange::Last(10).resolve(3), Some((0, 2)));
+    }
+
+    #[test]
+    fn byte_range_past_the_end_is_unsatisfiable() {
+        assert_eq!(ByteRange::From(5).resolve(3), None);
+        assert_eq!(ByteRange::From(3).resolve(3), None);
+        assert_eq!(ByteRange::FromTo(3, 4).resolve(3), None);
+        assert_eq!(ByteRange::Last(0).resolve(3), None);
+        assert_eq!(ByteRange::From(0).resolve(0), None);
+    }
+
+    #[test]
+    fn value_range_reads_the_slice() {
+        let conn = conn_with(&[("k", "abc")]);
+        let slice = get_value_range_db(&conn, "k".to_owned(), ByteRange::FromTo(1, 1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(slice.bytes, b"b");
+        assert_eq!((slice.start, slice.end, slice.total), (1, 1, 3));
+    }
+
+    #[test]
+    fn value_range_past_the_end_fails_with_its_length() {
+        let conn = conn_with(&[("k", "abc")]);
+        let err = get_value_range_db(&conn, "k".to_owned(), ByteRange::From(5)).unwrap_err();
+        let err = err.downcast_ref::<RangeNotSatisfiable>().unwrap();
+        assert_eq!(err.total, 3);
+    }
+}
@@ -0,0 +1,132 @@
+//! A bloom filter over keys, used to answer "definitely not present" without
+//! touching the database.
+//!
+//! Keys are hashed ASCII-case-insensitively, the same way SQLite's NOCASE
+//! collation compares them, so the filter never gives a false negative whether
+//! or not the key column is case-insensitive. Case-sensitive stores just see a
+//! few more false positives for keys that differ only in case.
+
+use std::hash::{BuildHasher, Hash, Hasher, RandomState};
+
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u64,
+    capacity: usize,
+    inserted: usize,
+    hasher: RandomState,
+}
+
+impl BloomFilter {
+    /// Sizes the filter so that it has roughly `false_positive_rate` once
+    /// `capacity` keys have been inserted.
+    pub fn with_capacity(capacity: usize, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(1);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as u64;
+        let num_hashes = (num_bits as f64 / capacity as f64 * ln2).round().max(1.0) as u64;
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            capacity,
+            inserted: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How many inserts there have been, counting repeats of the same key.
+    pub fn inserted(&self) -> usize {
+        self.inserted
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.bit_indexes(key) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.inserted += 1;
+    }
+
+    /// False means `key` was never inserted. True means it probably was.
+    pub fn might_contain(&self, key: &str) -> bool {
+        self.bit_indexes(key)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    // Double hashing: the i-th index is h1 + i * h2, which behaves about as
+    // well as `num_hashes` independent hashes.
+    fn bit_indexes(&self, key: &str) -> impl Iterator<Item = u64> {
+        let h1 = self.hash(0, key);
+        let h2 = self.hash(1, key) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    fn hash(&self, seed: u8, key: &str) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        seed.hash(&mut hasher);
+        for byte in key.bytes() {
+            hasher.write_u8(byte.to_ascii_lowercase());
+        }
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn swap_case(key: &str) -> String {
+        key.chars()
+            .map(|c| {
+                if c.is_ascii_uppercase() {
+                    c.to_ascii_lowercase()
+                } else {
+                    c.to_ascii_uppercase()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn no_false_negatives_in_either_case() {
+        let keys: Vec<String> = (0..2000)
+            .map(|i| format!("User/{i}/Profile-{}", i % 7))
+            .collect();
+        let mut filter = BloomFilter::with_capacity(keys.len(), 0.01);
+        for key in &keys {
+            filter.insert(key);
+        }
+        for key in &keys {
+            assert!(filter.might_contain(key), "{key}");
+            assert!(filter.might_contain(&swap_case(key)), "{key}");
+            assert!(filter.might_contain(&key.to_ascii_lowercase()), "{key}");
+            assert!(filter.might_contain(&key.to_ascii_uppercase()), "{key}");
+        }
+        assert_eq!(filter.inserted(), keys.len());
+    }
+
+    #[test]
+    fn no_false_negatives_past_capacity() {
+        let mut filter = BloomFilter::with_capacity(10, 0.01);
+        for i in 0..1000 {
+            filter.insert(&format!("Key{i}"));
+        }
+        for i in 0..1000 {
+            assert!(filter.might_contain(&format!("kEY{i}")));
+        }
+    }
+
+    #[test]
+    fn empty_filter_contains_nothing() {
+        let filter = BloomFilter::with_capacity(100, 0.01);
+        assert!(!filter.might_contain("anything"));
+        assert!(!filter.might_contain(""));
+    }
+}
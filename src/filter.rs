@@ -0,0 +1,224 @@
+//! A small filter language for listing items, compiled to a parameterized
+//! WHERE clause. Clients can combine conditions freely, but never get to
+//! write SQL themselves: every value they supply is bound as a parameter.
+//!
+//! In JSON, a filter looks like
+//! `{"and": [{"prefix_eq": "user/"}, {"value_len_gt": 1024}]}`.
+
+use anyhow::bail;
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+
+// Deep enough for any filter a person would write, shallow enough that
+// compiling one can't blow the stack.
+const MAX_DEPTH: usize = 32;
+
+/// A filter was nested too deeply to compile.
+#[derive(Debug)]
+pub struct FilterTooDeep {
+    pub max_depth: usize,
+}
+
+impl std::fmt::Display for FilterTooDeep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "filter is nested more than {} deep", self.max_depth)
+    }
+}
+
+impl std::error::Error for FilterTooDeep {}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    /// Keys starting with this prefix.
+    PrefixEq(String),
+    /// Values longer than this many bytes.
+    ValueLenGt(u64),
+    /// All of these hold. Empty matches everything.
+    And(Vec<Filter>),
+    /// At least one of these holds. Empty matches nothing.
+    Or(Vec<Filter>),
+}
+
+impl Filter {
+    /// Appends the filter as an SQL expression over the items table to
+    /// `sql`, and the values for its `?` placeholders to `params`.
+    pub(crate) fn to_sql(&self, sql: &mut String, params: &mut Vec<Value>) -> anyhow::Result<()> {
+        self.to_sql_at(0, sql, params)
+    }
+
+    fn to_sql_at(
+        &self,
+        depth: usize,
+        sql: &mut String,
+        params: &mut Vec<Value>,
+    ) -> anyhow::Result<()> {
+        if depth > MAX_DEPTH {
+            bail!(FilterTooDeep {
+                max_depth: MAX_DEPTH
+            });
+        }
+        match self {
+            Self::PrefixEq(prefix) => {
+                // A range rather than LIKE, so it can use the key index.
+                sql.push_str("(key >= ?");
+                params.push(Value::Text(prefix.clone()));
+                if let Some(end) = prefix_upper_bound(prefix) {
+                    sql.push_str(" AND key < ?");
+                    params.push(Value::Text(end));
+                }
+                sql.push(')');
+            }
+            Self::ValueLenGt(len) => {
+                sql.push_str("length(CAST(value AS BLOB)) > ?");
+                params.push(Value::Integer(i64::try_from(*len).unwrap_or(i64::MAX)));
+            }
+            Self::And(filters) | Self::Or(filters) => {
+                let (op, empty) = match self {
+                    Self::And(_) => (" AND ", "1"),
+                    _ => (" OR ", "0"),
+                };
+                if filters.is_empty() {
+                    sql.push_str(empty);
+                    return Ok(());
+                }
+                sql.push('(');
+                for (i, filter) in filters.iter().enumerate() {
+                    if i > 0 {
+                        sql.push_str(op);
+                    }
+                    filter.to_sql_at(depth + 1, sql, params)?;
+                }
+                sql.push(')');
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The smallest string greater than every string starting with `prefix`, or
+/// `None` if there isn't one (the prefix is empty, or all `char::MAX`).
+pub(crate) fn prefix_upper_bound(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let next = match last {
+            // Skip over the surrogate range, which aren't chars.
+            '\u{d7ff}' => Some('\u{e000}'),
+            _ => char::from_u32(u32::from(last) + 1),
+        };
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(filter: &Filter) -> anyhow::Result<(String, Vec<Value>)> {
+        let mut sql = String::new();
+        let mut params = Vec::new();
+        filter.to_sql(&mut sql, &mut params)?;
+        Ok((sql, params))
+    }
+
+    // `depth` levels of `And` around a single condition.
+    fn nested(depth: usize) -> Filter {
+        let mut filter = Filter::ValueLenGt(0);
+        for _ in 0..depth {
+            filter = Filter::And(vec![filter]);
+        }
+        filter
+    }
+
+    #[test]
+    fn upper_bound_bumps_the_last_char() {
+        assert_eq!(prefix_upper_bound("user/").as_deref(), Some("user0"));
+        assert_eq!(prefix_upper_bound("é").as_deref(), Some("ê"));
+    }
+
+    #[test]
+    fn upper_bound_of_empty_prefix_is_none() {
+        assert_eq!(prefix_upper_bound(""), None);
+    }
+
+    #[test]
+    fn upper_bound_carries_past_char_max() {
+        assert_eq!(prefix_upper_bound("a\u{10ffff}").as_deref(), Some("b"));
+        assert_eq!(prefix_upper_bound("\u{10ffff}\u{10ffff}"), None);
+    }
+
+    #[test]
+    fn upper_bound_skips_surrogates() {
+        assert_eq!(
+            prefix_upper_bound("a\u{d7ff}").as_deref(),
+            Some("a\u{e000}")
+        );
+    }
+
+    #[test]
+    fn empty_prefix_has_no_upper_bound_in_sql() {
+        let (sql, params) = compile(&Filter::PrefixEq(String::new())).unwrap();
+        assert_eq!(sql, "(key >= ?)");
+        assert_eq!(params, vec![Value::Text(String::new())]);
+    }
+
+    #[test]
+    fn empty_and_or() {
+        assert_eq!(compile(&Filter::And(vec![])).unwrap().0, "1");
+        assert_eq!(compile(&Filter::Or(vec![])).unwrap().0, "0");
+    }
+
+    #[test]
+    fn and_or_combine() {
+        let filter = Filter::Or(vec![
+            Filter::PrefixEq("a".to_owned()),
+            Filter::And(vec![Filter::ValueLenGt(1), Filter::ValueLenGt(2)]),
+        ]);
+        let (sql, params) = compile(&filter).unwrap();
+        assert_eq!(
+            sql,
+            "((key >= ? AND key < ?) OR (length(CAST(value AS BLOB)) > ? \
+             AND length(CAST(value AS BLOB)) > ?))"
+        );
+        assert_eq!(
+            params,
+            vec![
+                Value::Text("a".to_owned()),
+                Value::Text("b".to_owned()),
+                Value::Integer(1),
+                Value::Integer(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn nesting_up_to_max_depth_compiles() {
+        assert!(compile(&nested(MAX_DEPTH)).is_ok());
+    }
+
+    #[test]
+    fn nesting_past_max_depth_fails() {
+        let err = compile(&nested(MAX_DEPTH + 1)).unwrap_err();
+        let err = err.downcast_ref::<FilterTooDeep>().unwrap();
+        assert_eq!(err.max_depth, MAX_DEPTH);
+    }
+
+    #[test]
+    fn values_are_bound_not_interpolated() {
+        let hostile = "x') OR 1=1; DROP TABLE items; --";
+        let filter = Filter::And(vec![
+            Filter::PrefixEq(hostile.to_owned()),
+            Filter::ValueLenGt(u64::MAX),
+        ]);
+        let (sql, params) = compile(&filter).unwrap();
+        assert!(!sql.contains("DROP"), "{sql}");
+        assert!(!sql.contains(&i64::MAX.to_string()), "{sql}");
+        assert_eq!(sql.matches('?').count(), params.len());
+        assert_eq!(params[0], Value::Text(hostile.to_owned()));
+        assert_eq!(params[2], Value::Integer(i64::MAX));
+    }
+}
@@ -1,9 +1,134 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
 pub mod backgroundb;
+mod bloom;
+pub mod filter;
+pub mod resilient;
+pub mod sync;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Item {
     pub key: String,
     pub value: String,
 }
+
+/// One step of an atomic batch, see
+/// [`backgroundb::DatabaseClient::apply`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    Put {
+        key: String,
+        value: String,
+    },
+    /// Deleting a key that isn't there is not an error.
+    Delete {
+        key: String,
+    },
+    /// Aborts the whole batch unless `key` currently (i.e. after the ops
+    /// before it) holds exactly `value`.
+    CheckEquals {
+        key: String,
+        value: String,
+    },
+}
+
+/// One page of items in key order, optionally with the total number of items
+/// as of the same moment.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Page {
+    pub items: Vec<Item>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+/// A key and the size of its value in bytes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeySize {
+    pub key: String,
+    pub size: u64,
+}
+
+/// Everything a maintainer is likely to ask for when troubleshooting, in one
+/// place.
+#[derive(Serialize, Clone, Debug)]
+pub struct Diagnostics {
+    pub sqlite_version: String,
+    /// `PRAGMA user_version`.
+    pub schema_version: i64,
+    pub pragmas: BTreeMap<String, serde_json::Value>,
+    pub item_count: u64,
+    pub size_bytes: u64,
+    /// The output of `PRAGMA integrity_check`: just "ok" for a healthy
+    /// database, otherwise (up to a limit) one line per problem.
+    pub integrity_check: Vec<String>,
+    /// The most recent requests that took the database thread a long time,
+    /// oldest first.
+    pub slow_requests: Vec<SlowRequest>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SlowRequest {
+    pub request: String,
+    pub elapsed_ms: u64,
+}
+
+/// A request waiting for the database thread to pick it up.
+#[derive(Serialize, Clone, Debug)]
+pub struct QueuedRequest {
+    pub request: String,
+    pub waiting_ms: u64,
+}
+
+/// How single-key lookups were answered: a hit skipped the database
+/// entirely, a miss read it. There's no value cache, so for now the only hits
+/// are absent keys ruled out by the bloom filter.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Who holds a leadership lease, see
+/// [`backgroundb::DatabaseClient::acquire_leadership`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Leadership {
+    /// Whether the candidate that asked holds the lease now.
+    pub held: bool,
+    /// The current leader, unless the lease has run out.
+    pub leader: Option<String>,
+    /// When the current lease runs out, in milliseconds since the Unix epoch.
+    pub expires_at_ms: Option<i64>,
+}
+
+/// One object from the database schema, as recorded in `sqlite_master`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SchemaObject {
+    /// "table", "index" or "trigger".
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    /// The statement that created it.
+    pub sql: String,
+}
+
+/// A value held by more than one key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Duplicate {
+    pub value: String,
+    pub count: u64,
+    /// In key order.
+    pub keys: Vec<String>,
+}
+
+/// One row of `EXPLAIN QUERY PLAN`. Steps form a tree through `parent` (0 for
+/// the top level), and `detail` says what SQLite does at that step, e.g.
+/// "SEARCH items USING INDEX sqlite_autoindex_items_1 (key>? AND key<?)".
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlanStep {
+    pub id: i64,
+    pub parent: i64,
+    pub detail: String,
+}
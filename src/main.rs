@@ -1,25 +1,493 @@
+use activity::Activity;
+use anyhow::Context;
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::get,
+    body::{Body, Bytes},
+    extract::{rejection::JsonRejection, FromRequest, Path, Query, Request, State},
+    http::{
+        header::{ACCEPT_RANGES, AUTHORIZATION, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
     Json, Router,
 };
-use clap::Parser;
-use serde::Deserialize;
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use clap::{Parser, ValueEnum};
+use futures_util::StreamExt;
+use hyper_util::{
+    rt::{TokioExecutor, TokioTimer},
+    server::conn::auto,
+};
+use readiness::Readiness;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::RawValue};
+use sha2::{Digest, Sha256};
 use sqlite_async::{
-    backgroundb::{self, DatabaseClient},
-    Item,
+    backgroundb::{
+        self, Aggregate, ByteRange, CheckFailed, DatabaseClient, Direction, Explain, NotAnInteger,
+        NotJson, OpenOptions, RangeNotSatisfiable, Reopen, ResultTooLarge, ShuttingDown,
+        SpawnOptions, StorageFull, Synchronous, UnknownDatabase, UnknownIndex, ValueTooLarge,
+    },
+    filter::{Filter, FilterTooDeep},
+    Item, Op, Page,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use std::path::PathBuf;
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+use tower_http::{
+    cors::{AllowOrigin, Any, CorsLayer},
+    set_header::SetResponseHeaderLayer,
+};
+use uuid::Uuid;
+
+mod activity;
+mod readiness;
+mod wire;
+mod ws;
 
 #[derive(Parser, Debug)]
 struct Args {
-    #[arg(long, help = "Path to the database file")]
+    #[arg(
+        long,
+        help = "Path to the database file, or a file: URI such as file::memory:?cache=shared"
+    )]
     database: PathBuf,
 
+    #[arg(
+        long,
+        help = "Expect the items table to exist already instead of creating it"
+    )]
+    no_create_table: bool,
+
+    #[arg(
+        long,
+        help = "Treat keys that differ only in ASCII case as the same key (new databases only)"
+    )]
+    case_insensitive_keys: bool,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Keep retrying to open the database for this long while another process has it locked"
+    )]
+    open_retry: Option<u64>,
+
+    #[arg(
+        long,
+        help = "How hard to work to get each commit onto disk (PRAGMA synchronous)"
+    )]
+    synchronous: Option<SyncMode>,
+
+    #[arg(
+        long,
+        value_name = "PAGES",
+        help = "Checkpoint the WAL once it reaches this many pages, or never if 0 (PRAGMA wal_autocheckpoint)"
+    )]
+    wal_autocheckpoint: Option<u32>,
+
+    #[arg(
+        long,
+        help = "Open the database in SQLite's shared-cache mode, e.g. to share an in-memory database between connections"
+    )]
+    shared_cache: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Load items from this JSON array or NDJSON file before serving"
+    )]
+    seed: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "seed",
+        help = "Let --seed overwrite keys that already exist instead of skipping them"
+    )]
+    seed_overwrite: bool,
+
+    #[arg(
+        long,
+        value_name = "ALIAS=PATH",
+        value_parser = parse_attachment,
+        help = "Attach another database file read-only under ALIAS (repeatable)"
+    )]
+    attach: Vec<(String, PathBuf)>,
+
+    #[arg(
+        long,
+        value_name = "NAME=EXPR",
+        value_parser = parse_index,
+        help = "Index items by this SQL expression, e.g. type=json_extract(value,'$.type') (repeatable)"
+    )]
+    index: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        value_name = "PREFIX=VALUE",
+        value_parser = parse_default_value,
+        help = "Answer a GET for a missing key under PREFIX with VALUE instead of 404; the longest matching prefix wins (repeatable)"
+    )]
+    default_value: Vec<(String, String)>,
+
     #[arg(long, default_value = "127.0.0.1:8080")]
     addr: String,
+
+    #[arg(
+        long,
+        requires = "tls_key",
+        help = "Serve HTTPS with this PEM certificate (chain) instead of plain HTTP"
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "tls_cert",
+        help = "The PEM private key for --tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Also serve the binary wire protocol (length-prefixed bincode) on this address"
+    )]
+    wire_addr: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 128,
+        help = "Stop reading from a WebSocket or wire connection while it has this many requests outstanding"
+    )]
+    max_in_flight_per_connection: usize,
+
+    #[arg(
+        long,
+        help = "Batch point reads that arrive within this many milliseconds of each other"
+    )]
+    coalesce_reads_ms: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Commit writes that arrive within this many milliseconds of each other together"
+    )]
+    coalesce_writes_ms: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Run the database loop on this runtime's blocking pool instead of a dedicated thread"
+    )]
+    db_on_runtime: bool,
+
+    #[arg(
+        long,
+        help = "Before serving, regenerate the item count and indexes from the items table"
+    )]
+    rebuild_derived: bool,
+
+    #[arg(
+        long,
+        help = "Run read requests with PRAGMA query_only on, so a read that tries to write fails"
+    )]
+    query_only_reads: bool,
+
+    #[arg(long, help = "Name for the database thread [default: bgdb-worker]")]
+    db_thread_name: Option<String>,
+
+    #[arg(
+        long,
+        allow_negative_numbers = true,
+        help = "Nice value for the database thread, e.g. 10 to favor serving HTTP (Linux only)"
+    )]
+    db_thread_nice: Option<i32>,
+
+    #[arg(
+        long,
+        help = "Keep an in-memory bloom filter of keys to skip lookups of absent keys"
+    )]
+    bloom_filter: bool,
+
+    #[arg(long, help = "Cap on the number of rows any one listing may return")]
+    max_result_rows: Option<usize>,
+
+    #[arg(
+        long,
+        requires = "max_result_rows",
+        help = "Cut listings down to --max-result-rows instead of rejecting them with a 400"
+    )]
+    clamp_result_rows: bool,
+
+    #[arg(long, value_name = "BYTES", help = "Longest value any write may store")]
+    max_value_bytes: Option<usize>,
+
+    #[arg(
+        long,
+        requires = "max_value_bytes",
+        help = "Cut longer values down to --max-value-bytes instead of rejecting them with a 413"
+    )]
+    truncate_values: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Reopen the database connection after N I/O or corruption errors in a row"
+    )]
+    reopen_after_errors: Option<u32>,
+
+    #[arg(
+        long,
+        default_value_t = 3,
+        requires = "reopen_after_errors",
+        help = "Give up after this many reopens that don't bring the connection back"
+    )]
+    max_reopens: u32,
+
+    #[arg(
+        long,
+        help = "Return internal error details to clients instead of a correlation ID"
+    )]
+    verbose_errors: bool,
+
+    #[arg(
+        long,
+        env = "BGDB_ADMIN_TOKEN",
+        hide_env_values = true,
+        // An empty token would let in anyone sending `Bearer ` with nothing after.
+        value_parser = clap::builder::NonEmptyStringValueParser::new(),
+        help = "Serve the /admin routes, requiring this bearer token on them"
+    )]
+    admin_token: Option<String>,
+
+    #[arg(
+        long,
+        help = "Allow cross-origin requests from this origin (repeatable, or * for any)"
+    )]
+    cors_origin: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Prime the page cache before serving, by walking the key index or every row"
+    )]
+    warmup: Option<Warmup>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Shut down gracefully after this many seconds without any requests"
+    )]
+    idle_timeout: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Answer 503 to any request whose handler takes longer than this"
+    )]
+    response_timeout: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "MINS",
+        // At most a year, which keeps the conversion to seconds from overflowing.
+        value_parser = clap::value_parser!(u64).range(1..=525_600),
+        help = "Run routine maintenance (WAL checkpoint, PRAGMA optimize) this often, at the next lull in requests"
+    )]
+    maintenance_interval: Option<u64>,
+
+    #[arg(
+        long,
+        requires = "maintenance_interval",
+        help = "Also run an incremental vacuum during maintenance (needs auto_vacuum = INCREMENTAL)"
+    )]
+    maintenance_vacuum: bool,
+
+    #[arg(
+        long,
+        help = "Abort the process after logging a panic anywhere, e.g. so a supervisor restarts it"
+    )]
+    abort_on_panic: bool,
+
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+// Connection-level settings for the HTTP server.
+#[derive(clap::Args, Debug)]
+struct HttpArgs {
+    #[arg(long, help = "Close HTTP/1.1 connections after each response")]
+    no_keepalive: bool,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Close an HTTP/1.1 connection whose next request's headers take longer than this"
+    )]
+    header_read_timeout: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Ping idle HTTP/2 connections this often, closing the ones that don't answer"
+    )]
+    http2_keepalive_interval: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 20,
+        requires = "http2_keepalive_interval",
+        help = "How long to wait for the answer to an HTTP/2 ping"
+    )]
+    http2_keepalive_timeout: u64,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Limit how many requests a client may have in flight on one HTTP/2 connection"
+    )]
+    http2_max_streams: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Warmup {
+    Index,
+    Full,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SyncMode {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl From<SyncMode> for Synchronous {
+    fn from(mode: SyncMode) -> Self {
+        match mode {
+            SyncMode::Off => Synchronous::Off,
+            SyncMode::Normal => Synchronous::Normal,
+            SyncMode::Full => Synchronous::Full,
+            SyncMode::Extra => Synchronous::Extra,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    db_client: DatabaseClient,
+    verbose_errors: bool,
+    activity: Activity,
+    max_in_flight_per_connection: usize,
+    // Values for missing keys, by prefix, longest prefix first.
+    default_values: Arc<[(String, String)]>,
+    // The length writes cut values down to, when they're cut rather than
+    // rejected. The client does the cutting; this is just for telling the
+    // caller.
+    truncate_values_over: Option<usize>,
+}
+
+impl AppState {
+    // An `x-truncated` header for the response to a write of `value`, if
+    // the value stored will be cut short.
+    fn truncated_header(&self, value: &str) -> Option<[(&'static str, &'static str); 1]> {
+        let max = self.truncate_values_over?;
+        (value.len() > max).then_some([("x-truncated", "true")])
+    }
+
+    fn default_value(&self, key: &str) -> Option<&str> {
+        self.default_values
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Turns an error from the database into a response. Anything we don't
+    // have a more specific status for is a 500, and unless verbose errors are
+    // on, its details only go to the log, keyed by an ID the client can quote.
+    fn error(&self, err: anyhow::Error) -> ApiError {
+        if err.is::<StorageFull>() {
+            return ApiError::new(StatusCode::INSUFFICIENT_STORAGE, err.to_string());
+        }
+        if err.is::<CheckFailed>() {
+            return ApiError::new(StatusCode::CONFLICT, err.to_string());
+        }
+        if err.is::<ShuttingDown>() {
+            return ApiError::new(StatusCode::SERVICE_UNAVAILABLE, err.to_string());
+        }
+        if err.is::<UnknownDatabase>() || err.is::<UnknownIndex>() {
+            return ApiError::new(StatusCode::NOT_FOUND, err.to_string());
+        }
+        if err.is::<ValueTooLarge>() {
+            return ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, err.to_string());
+        }
+        if err.is::<NotJson>()
+            || err.is::<NotAnInteger>()
+            || err.is::<ResultTooLarge>()
+            || err.is::<FilterTooDeep>()
+        {
+            return ApiError::new(StatusCode::BAD_REQUEST, err.to_string());
+        }
+        let correlation_id = Uuid::new_v4();
+        tracing::error!(%correlation_id, "internal error: {err:#}");
+        let message = if self.verbose_errors {
+            format!("{err:#}")
+        } else {
+            format!("internal error (correlation id {correlation_id})")
+        };
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+}
+
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn not_found() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not found")
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+// A JSON body, like `Json`, except that a body we can't parse is reported in
+// the same shape as every other error rather than as axum's plain-text
+// rejection.
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ApiError))]
+struct AppJson<T>(T);
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let status = match rejection {
+            // Keep 415 for a missing `Content-Type: application/json`; it's
+            // not the body that's wrong.
+            JsonRejection::MissingJsonContentType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        Self::new(status, rejection.body_text())
+    }
 }
 
 #[derive(Deserialize)]
@@ -27,60 +495,1882 @@ struct ValuePayload {
     value: String,
 }
 
+#[derive(Deserialize)]
+struct PutParams {
+    #[serde(default)]
+    expand: bool,
+    #[serde(default)]
+    if_changed: bool,
+    #[serde(default)]
+    durable: bool,
+    #[serde(default, rename = "return")]
+    returning: Returning,
+}
+
+// What a successful write responds with, after the `Prefer: return=` values
+// of RFC 7240.
+#[derive(Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Returning {
+    // Just the status.
+    #[default]
+    Minimal,
+    // 200 with the row as stored, read back from the write itself.
+    Representation,
+}
+
+#[derive(Deserialize)]
+struct GetParams {
+    value_json_path: Option<String>,
+    #[serde(default)]
+    decode_json: bool,
+    #[serde(default)]
+    count_read: bool,
+}
+
+#[derive(Deserialize)]
+struct DecodeParams {
+    #[serde(default)]
+    decode_json: bool,
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+    value_json_path: Option<String>,
+    #[serde(default)]
+    decode_json: bool,
+    page: Option<u32>,
+    page_size: Option<u32>,
+    #[serde(default)]
+    with_total: bool,
+    #[serde(default)]
+    sort: ListSort,
+    #[serde(default)]
+    desc: bool,
+    limit: Option<u32>,
+    // Skip rows that can't be read instead of failing the listing, reporting
+    // how many were skipped in an `x-skipped-rows` header.
+    #[serde(default)]
+    skip_bad_rows: bool,
+    // With a delimiter, list the distinct key prefixes one level below
+    // `prefix` instead of items, like S3's list with a delimiter.
+    prefix: Option<String>,
+    delimiter: Option<String>,
+}
+
+impl ListParams {
+    // The parameters that were given, leaving out `sort` when it's the
+    // default.
+    fn given(&self) -> impl Iterator<Item = &'static str> {
+        [
+            ("value_json_path", self.value_json_path.is_some()),
+            ("decode_json", self.decode_json),
+            ("page", self.page.is_some()),
+            ("page_size", self.page_size.is_some()),
+            ("with_total", self.with_total),
+            ("sort", self.sort != ListSort::Key),
+            ("desc", self.desc),
+            ("limit", self.limit.is_some()),
+            ("skip_bad_rows", self.skip_bad_rows),
+            ("prefix", self.prefix.is_some()),
+            ("delimiter", self.delimiter.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, given)| given.then_some(name))
+    }
+
+    // Each kind of listing takes only some of the parameters, and the others
+    // are refused rather than quietly ignored.
+    fn only(&self, listing: &str, used: &[&str]) -> Result<(), ApiError> {
+        match self.given().find(|name| !used.contains(name)) {
+            Some(name) => Err(ApiError::bad_request(format!(
+                "{name} doesn't apply to {listing}"
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ListSort {
+    #[default]
+    Key,
+    Value,
+}
+
+const DEFAULT_PAGE_SIZE: u32 = 100;
+
+// With `?decode_json=true`, values go out embedded in the response as JSON
+// rather than as strings that hold JSON. These mirror `Item` and `Page`.
+#[derive(Serialize)]
+struct DecodedItem {
+    key: String,
+    value: Box<RawValue>,
+}
+
+#[derive(Serialize)]
+struct DecodedPage {
+    items: Vec<DecodedItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<u64>,
+}
+
+fn decode_item(item: Item) -> Result<DecodedItem, ApiError> {
+    match RawValue::from_string(item.value) {
+        Ok(value) => Ok(DecodedItem {
+            key: item.key,
+            value,
+        }),
+        Err(_) => Err(ApiError::bad_request(NotJson { key: item.key }.to_string())),
+    }
+}
+
+fn item_response(item: Item, decode_json: bool) -> Result<Response, ApiError> {
+    if decode_json {
+        Ok(Json(decode_item(item)?).into_response())
+    } else {
+        Ok(Json(item).into_response())
+    }
+}
+
+fn items_response(items: Vec<Item>, decode_json: bool) -> Result<Response, ApiError> {
+    if decode_json {
+        let items = items
+            .into_iter()
+            .map(decode_item)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Json(items).into_response())
+    } else {
+        Ok(Json(items).into_response())
+    }
+}
+
+fn page_response(page: Page, decode_json: bool) -> Result<Response, ApiError> {
+    if decode_json {
+        let items = page
+            .items
+            .into_iter()
+            .map(decode_item)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Json(DecodedPage {
+            items,
+            total: page.total,
+        })
+        .into_response())
+    } else {
+        Ok(Json(page).into_response())
+    }
+}
+
+#[derive(Deserialize)]
+struct MultiGetParams {
+    #[serde(rename = "as", default)]
+    shape: MultiGetShape,
+}
+
+#[derive(Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum MultiGetShape {
+    // One entry per requested key, in order, null where it's missing.
+    #[default]
+    List,
+    // An object of just the keys that were found.
+    Map,
+}
+
+// Bucket hashes travel as 16-digit hex strings, since JSON numbers can't be
+// relied on to hold all 64 bits.
+#[derive(Deserialize)]
+struct DiffPayload {
+    prefix_len: usize,
+    buckets: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct IndexParams {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct KeysParams {
+    #[serde(default)]
+    with_size: bool,
+}
+
+#[derive(Deserialize)]
+struct CountParams {
+    #[serde(default)]
+    estimate: bool,
+}
+
+#[derive(Deserialize)]
+struct RangeParams {
+    start: String,
+    end: String,
+}
+
+#[derive(Deserialize)]
+struct AggregateParams {
+    #[serde(default)]
+    prefix: String,
+    op: AggregateOp,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl From<AggregateOp> for Aggregate {
+    fn from(op: AggregateOp) -> Self {
+        match op {
+            AggregateOp::Sum => Aggregate::Sum,
+            AggregateOp::Avg => Aggregate::Avg,
+            AggregateOp::Min => Aggregate::Min,
+            AggregateOp::Max => Aggregate::Max,
+            AggregateOp::Count => Aggregate::Count,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExplainParams {
+    op: ExplainOp,
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ExplainOp {
+    Scan,
+    Prefix,
+    Range,
+}
+
+#[derive(Deserialize)]
+struct TopParams {
+    #[serde(default = "default_top_n")]
+    n: u32,
+}
+
+fn default_top_n() -> u32 {
+    10
+}
+
+#[derive(Deserialize)]
+struct BatchParams {
+    #[serde(default)]
+    best_effort: bool,
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
+    install_panic_hook(args.abort_on_panic);
+
+    let open_options = OpenOptions {
+        create_if_missing: !args.no_create_table,
+        attach: args.attach,
+        case_insensitive_keys: args.case_insensitive_keys,
+        synchronous: args.synchronous.map(Synchronous::from),
+        wal_autocheckpoint: args.wal_autocheckpoint,
+        indexes: args.index,
+        shared_cache: args.shared_cache,
+    };
+    let conn = open_with_retry(
+        args.database.clone(),
+        open_options.clone(),
+        Duration::from_secs(args.open_retry.unwrap_or(0)),
+    )
+    .await?;
+    if args.rebuild_derived {
+        backgroundb::rebuild_derived(&conn).context("Failed to rebuild derived state")?;
+    }
+    let options = SpawnOptions {
+        read_coalesce_window: args.coalesce_reads_ms.map(Duration::from_millis),
+        write_coalesce_window: args.coalesce_writes_ms.map(Duration::from_millis),
+        bloom_filter: args.bloom_filter,
+        max_result_rows: args.max_result_rows,
+        clamp_result_rows: args.clamp_result_rows,
+        reopen: args.reopen_after_errors.map(|after_errors| Reopen {
+            path: args.database,
+            options: open_options,
+            after_errors,
+            max_reopens: args.max_reopens,
+        }),
+        thread_name: args.db_thread_name,
+        thread_nice: args.db_thread_nice,
+        query_only_reads: args.query_only_reads,
+        max_value_bytes: args.max_value_bytes,
+        truncate_values: args.truncate_values,
+    };
+    let db_client = if args.db_on_runtime {
+        backgroundb::spawn_on_current_runtime_with_options(conn, options)
+    } else {
+        backgroundb::spawn_with_options(conn, options)
+    };
+
+    if let Some(path) = &args.seed {
+        seed(&db_client, path, args.seed_overwrite)
+            .await
+            .with_context(|| format!("Failed to seed from {}", path.display()))?;
+    }
 
-    let db_client = backgroundb::spawn(backgroundb::open(args.database)?);
+    let activity = Activity::new();
+    let readiness = Readiness::default();
+
+    // Admin routes are only there at all when there's a token to guard them.
+    let mut admin = Router::new();
+    if let Some(token) = args.admin_token {
+        admin = admin
+            .route("/top", get(top_by_size))
+            .route("/duplicates", get(find_duplicates))
+            .route("/flush", post(flush))
+            .route("/schema", get(get_schema))
+            .route("/explain", get(explain))
+            .route("/diagnostics", get(diagnostics))
+            .route("/inflight", get(inflight))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::<str>::from(token),
+                require_admin_token,
+            ));
+    }
+
+    let state = AppState {
+        db_client: db_client.clone(),
+        verbose_errors: args.verbose_errors,
+        activity: activity.clone(),
+        max_in_flight_per_connection: args.max_in_flight_per_connection.max(1),
+        default_values: {
+            let mut default_values = args.default_value.clone();
+            default_values.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+            default_values.into()
+        },
+        truncate_values_over: args.max_value_bytes.filter(|_| args.truncate_values),
+    };
 
     // Build the axum application with routes
-    let app = Router::new()
-        .route("/items", get(get_all_items))
-        .route("/items/:key", get(get_item).put(put_item))
-        .with_state(db_client.clone());
+    let mut app = Router::new()
+        .route(
+            "/items",
+            get(get_all_items).post(put_items).delete(delete_range),
+        )
+        // Note that these shadow the keys "mget", "count" and "window" for the
+        // routes below.
+        .route("/items/mget", post(get_items))
+        .route("/items/count", get(count_items))
+        .route("/items/window", get(get_window))
+        .route("/items/:key", get(get_item).put(put_item).head(item_exists))
+        .route("/items/:key/raw", get(get_item_raw).put(put_item_raw))
+        .route("/items/:key/pop", post(pop_item))
+        .route("/items/:key/archive", post(archive_item))
+        .route("/items/:key/decrement", post(decrement_item))
+        .route("/items/:key/reads", get(get_read_count))
+        .route("/items/:key/tags", get(get_tags))
+        .route("/items/:key/tags/:tag", put(add_tag).delete(remove_tag))
+        .route(
+            "/bin/items/:key",
+            get(get_item_bin_key).put(put_item_bin_key),
+        )
+        .route("/attached/:alias/items", get(get_all_attached_items))
+        .route("/attached/:alias/items/:key", get(get_attached_item))
+        .route("/keys", get(get_keys))
+        .route("/query", post(query))
+        .route("/aggregate", get(aggregate))
+        .route("/txn", post(apply))
+        .route("/indexes/:name", get(get_by_index))
+        .route("/tags/:tag", get(get_by_tag))
+        .route(
+            "/leader/:key",
+            post(acquire_leadership).put(renew_leadership),
+        )
+        .route("/blobs", post(put_blob))
+        .route("/blobs/:hash", get(get_blob))
+        .route("/sync/diff", post(sync_diff))
+        .route("/metrics", get(metrics))
+        .nest("/admin", admin)
+        .route("/ws", get(ws::upgrade))
+        .with_state(state.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            readiness.clone(),
+            readiness::gate,
+        ))
+        .route(
+            "/readyz",
+            get(readiness::readyz).with_state(readiness.clone()),
+        );
+    if let Some(secs) = args.response_timeout {
+        app = app.layer(axum::middleware::from_fn_with_state(
+            Duration::from_secs(secs),
+            response_timeout,
+        ));
+    }
+    app = app.layer(axum::middleware::from_fn(limit_path_segments));
+    app = app.layer(SetResponseHeaderLayer::overriding(
+        HeaderName::from_static("x-bgdb-version"),
+        HeaderValue::from_str(&version())?,
+    ));
+    if !args.cors_origin.is_empty() {
+        app = app.layer(cors_layer(&args.cors_origin)?);
+    }
+    let idle_timeout = args.idle_timeout.map(Duration::from_secs);
+    let maintenance_interval = args
+        .maintenance_interval
+        .map(|mins| Duration::from_secs(mins * 60));
+    if idle_timeout.is_some() || maintenance_interval.is_some() {
+        app = app.layer(axum::middleware::from_fn_with_state(
+            activity.clone(),
+            activity::track,
+        ));
+    }
 
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .context("Failed to load the TLS certificate and key")?,
+        ),
+        _ => None,
+    };
     let listener = tokio::net::TcpListener::bind(&args.addr).await?;
-    tracing::info!("listening on {}", args.addr);
+    tracing::info!(tls = tls.is_some(), "listening on {}", args.addr);
+    let wire_listener = match &args.wire_addr {
+        Some(wire_addr) => {
+            let wire_listener = tokio::net::TcpListener::bind(wire_addr).await?;
+            tracing::info!("serving the wire protocol on {wire_addr}");
+            Some(wire_listener)
+        }
+        None => None,
+    };
+
+    // Warming up can take a while on a big database, so it happens while
+    // already listening, with requests turned away until it's done. A failed
+    // warmup only means a colder cache, so it doesn't stop the server. Wire
+    // connections, which have no status to turn requests away with, aren't
+    // accepted until then.
+    tokio::spawn({
+        let db_client = db_client.clone();
+        let warmup = args.warmup;
+        async move {
+            if let Some(warmup) = warmup {
+                if let Err(err) = db_client.warmup(warmup == Warmup::Full).await {
+                    tracing::error!("warmup failed: {err:#}");
+                }
+            }
+            readiness.set_ready();
+            tracing::info!("ready");
+            if let Some(wire_listener) = wire_listener {
+                tokio::spawn(wire::serve(wire_listener, state));
+            }
+        }
+    });
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async move {
-            let _ = tokio::signal::ctrl_c().await;
+    if let Some(interval) = maintenance_interval {
+        tokio::spawn(maintain(
+            db_client.clone(),
+            activity.clone(),
+            interval,
+            args.maintenance_vacuum,
+        ));
+    }
+
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            let idle = async {
+                match idle_timeout {
+                    Some(timeout) => activity.wait_until_idle(timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = idle => tracing::info!("idle timeout reached"),
+            }
             tracing::debug!("beginning graceful shutdown");
             let _ = db_client.shutdown().await;
-        })
-        .await?;
+            handle.graceful_shutdown(None);
+        }
+    });
+
+    let listener = listener.into_std()?;
+    let app = app.into_make_service();
+    match tls {
+        Some(config) => {
+            let mut server = axum_server::from_tcp_rustls(listener, config).handle(handle);
+            configure_http(server.http_builder(), &args.http);
+            server.serve(app).await?;
+        }
+        None => {
+            let mut server = axum_server::from_tcp(listener).handle(handle);
+            configure_http(server.http_builder(), &args.http);
+            server.serve(app).await?;
+        }
+    }
     tracing::info!("graceful shutdown complete");
     Ok(())
 }
 
+// Every connection is served as HTTP/1.1 or HTTP/2, whichever the client
+// speaks. Over TLS that's settled by ALPN; cleartext HTTP/2 is recognized by
+// its connection preface (i.e. "prior knowledge"), so clients that want it
+// have to start with it rather than ask for an upgrade.
+fn configure_http(http: &mut auto::Builder<TokioExecutor>, args: &HttpArgs) {
+    http.http1().keep_alive(!args.no_keepalive);
+    if let Some(secs) = args.header_read_timeout {
+        http.http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(Duration::from_secs(secs));
+    }
+    let mut http2 = http.http2();
+    http2.timer(TokioTimer::new());
+    if let Some(streams) = args.http2_max_streams {
+        http2.max_concurrent_streams(streams);
+    }
+    if let Some(secs) = args.http2_keepalive_interval {
+        http2
+            .keep_alive_interval(Duration::from_secs(secs))
+            .keep_alive_timeout(Duration::from_secs(args.http2_keepalive_timeout));
+    }
+}
+
+// Sends panics to the log, with a backtrace, instead of to stderr. The hook
+// runs before any unwinding, so it sees panics that are caught afterwards
+// (e.g. on the database thread) too, and with `abort` it ends the process even
+// for those.
+fn install_panic_hook(abort: bool) {
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("(non-string payload)");
+        let location = info.location().map(ToString::to_string);
+        let thread = std::thread::current();
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        tracing::error!(
+            payload,
+            ?location,
+            thread = thread.name(),
+            %backtrace,
+            "panicked"
+        );
+        if abort {
+            std::process::abort();
+        }
+    }));
+}
+
+// How long requests have to let up for before scheduled maintenance runs.
+const MAINTENANCE_LULL: Duration = Duration::from_secs(5);
+
+// Runs maintenance every `interval`, each time after waiting for a lull, so it
+// doesn't hold up a burst of requests queued behind it on the database thread.
+async fn maintain(
+    db_client: DatabaseClient,
+    activity: Activity,
+    interval: Duration,
+    incremental_vacuum: bool,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes right away.
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        activity.wait_until_idle(MAINTENANCE_LULL).await;
+        match db_client.maintain(incremental_vacuum).await {
+            Ok(()) => {}
+            Err(err) if err.is::<ShuttingDown>() => return,
+            Err(err) => tracing::error!("maintenance failed: {err:#}"),
+        }
+    }
+}
+
+// Loads items from `path`, either one JSON array of items or one item per line
+// (NDJSON), in batches of one transaction each.
+async fn seed(
+    db_client: &DatabaseClient,
+    path: &std::path::Path,
+    overwrite: bool,
+) -> anyhow::Result<()> {
+    const BATCH_SIZE: usize = 1000;
+    let contents = tokio::fs::read_to_string(path).await?;
+    let items: Vec<Item> = if contents.trim_start().starts_with('[') {
+        serde_json::from_str(&contents)?
+    } else {
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("Invalid item on line {}", i + 1))
+            })
+            .collect::<anyhow::Result<_>>()?
+    };
+
+    let total = items.len();
+    let mut written = 0;
+    for batch in items.chunks(BATCH_SIZE) {
+        if overwrite {
+            db_client.put_items(batch.to_vec()).await?;
+            written += batch.len();
+        } else {
+            written += db_client.put_items_if_absent(batch.to_vec()).await?;
+        }
+    }
+    tracing::info!(total, written, "seeded {}", path.display());
+    Ok(())
+}
+
+// Opens the database, retrying with exponential backoff for up to `retry_for`
+// as long as the failure is another process holding a lock on the file.
+// Anything else fails right away.
+async fn open_with_retry(
+    path: PathBuf,
+    options: OpenOptions,
+    retry_for: Duration,
+) -> anyhow::Result<rusqlite::Connection> {
+    const MAX_BACKOFF: Duration = Duration::from_secs(5);
+    let deadline = Instant::now() + retry_for;
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        match backgroundb::open_with_options(path.clone(), options.clone()) {
+            Ok(conn) => return Ok(conn),
+            Err(err) if backgroundb::is_locked(&err) && Instant::now() < deadline => {
+                let delay = backoff.min(deadline.saturating_duration_since(Instant::now()));
+                tracing::warn!(?delay, "database is locked, retrying open: {err:#}");
+                tokio::time::sleep(delay).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// The crate version, plus the commit it was built from when that's known, in
+// semver build-metadata form, e.g. "0.1.0+1a2b3c4".
+fn version() -> String {
+    let version = env!("CARGO_PKG_VERSION");
+    match option_env!("BGDB_GIT_COMMIT") {
+        Some(commit) => format!("{version}+{commit}"),
+        None => version.to_owned(),
+    }
+}
+
+fn parse_attachment(arg: &str) -> Result<(String, PathBuf), String> {
+    match arg.split_once('=') {
+        Some((alias, path)) => Ok((alias.to_owned(), PathBuf::from(path))),
+        None => Err("expected ALIAS=PATH".to_owned()),
+    }
+}
+
+fn parse_index(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, expression)) => Ok((name.to_owned(), expression.to_owned())),
+        None => Err("expected NAME=EXPR".to_owned()),
+    }
+}
+
+fn parse_default_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((prefix, value)) => Ok((prefix.to_owned(), value.to_owned())),
+        None => Err("expected PREFIX=VALUE".to_owned()),
+    }
+}
+
+fn cors_layer(origins: &[String]) -> anyhow::Result<CorsLayer> {
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin).with_context(|| format!("Invalid origin {origin:?}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(Any)
+        .allow_headers(Any))
+}
+
 async fn get_all_items(
-    State(db_client): State<DatabaseClient>,
-) -> Result<impl IntoResponse, StatusCode> {
-    match db_client.get_all_items().await {
-        Ok(items) => Ok(Json(items)),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    Query(params): Query<ListParams>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    if let Some(delimiter) = &params.delimiter {
+        params.only("a listing by delimiter", &["prefix", "delimiter"])?;
+        if delimiter.is_empty() {
+            return Err(ApiError::bad_request("delimiter can't be empty"));
+        }
+        let prefix = params.prefix.clone().unwrap_or_default();
+        return match state
+            .db_client
+            .list_prefixes(prefix, delimiter.clone())
+            .await
+        {
+            Ok(prefixes) => Ok(Json(prefixes).into_response()),
+            Err(err) => Err(state.error(err)),
+        };
+    }
+    if params.prefix.is_some() {
+        return Err(ApiError::bad_request("prefix needs a delimiter"));
+    }
+    if params.sort == ListSort::Value {
+        params.only("sort=value", &["sort", "desc", "limit", "decode_json"])?;
+        let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        return match state
+            .db_client
+            .get_all_sorted_by_value(params.desc, limit)
+            .await
+        {
+            Ok(items) => items_response(items, params.decode_json),
+            Err(err) => Err(state.error(err)),
+        };
+    }
+    if params.page.is_some() || params.page_size.is_some() {
+        params.only(
+            "paging",
+            &["page", "page_size", "with_total", "decode_json"],
+        )?;
+        let page = params.page.unwrap_or(0);
+        let page_size = params.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        return match state
+            .db_client
+            .get_page(page, page_size, params.with_total)
+            .await
+        {
+            Ok(page) => page_response(page, params.decode_json),
+            Err(err) => Err(state.error(err)),
+        };
+    }
+    if params.skip_bad_rows {
+        params.only("skip_bad_rows", &["skip_bad_rows", "decode_json"])?;
+        return match state.db_client.get_all_items_lossy().await {
+            Ok((items, skipped)) => {
+                let response = items_response(items, params.decode_json)?;
+                Ok(([("x-skipped-rows", skipped.to_string())], response).into_response())
+            }
+            Err(err) => Err(state.error(err)),
+        };
+    }
+
+    params.only("a full listing", &["value_json_path", "decode_json"])?;
+    let result = match params.value_json_path {
+        Some(path) => {
+            check_json_path(&path)?;
+            state.db_client.get_all_items_projected(path).await
+        }
+        None => state.db_client.get_all_items().await,
+    };
+    match result {
+        Ok(items) => items_response(items, params.decode_json),
+        Err(err) => Err(state.error(err)),
     }
 }
 
 async fn get_item(
     Path(key): Path<String>,
-    State(db_client): State<DatabaseClient>,
-) -> Result<impl IntoResponse, StatusCode> {
-    match db_client.get_item(key).await {
-        Ok(Some(item)) => Ok((StatusCode::OK, Json(item))),
-        Ok(None) => Err(StatusCode::NOT_FOUND),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    Query(GetParams {
+        value_json_path,
+        decode_json,
+        count_read,
+    }): Query<GetParams>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    // Counted off to the side, so the read doesn't wait on it.
+    if count_read {
+        let db_client = state.db_client.clone();
+        let key = key.clone();
+        tokio::spawn(async move {
+            if let Err(err) = db_client.count_read(key).await {
+                tracing::warn!("failed to count a read: {err:#}");
+            }
+        });
+    }
+    // Projections always read the database, so they're always a miss. They
+    // also never fall back to a default value, which has nothing to project.
+    let (result, cached) = match value_json_path {
+        Some(path) => {
+            check_json_path(&path)?;
+            (state.db_client.get_item_projected(key, path).await, false)
+        }
+        None => match state
+            .db_client
+            .get_item_with_cache_status(key.clone())
+            .await
+        {
+            Ok((None, cached)) => {
+                let item = state.default_value(&key).map(|value| Item {
+                    key,
+                    value: value.to_owned(),
+                });
+                (Ok(item), cached)
+            }
+            Ok((item, cached)) => (Ok(item), cached),
+            Err(err) => (Err(err), false),
+        },
+    };
+    let response = match result {
+        Ok(Some(item)) => item_response(item, decode_json)?,
+        Ok(None) => ApiError::not_found().into_response(),
+        Err(err) => return Err(state.error(err)),
+    };
+    let x_cache = if cached { "hit" } else { "miss" };
+    Ok(([("x-cache", x_cache)], response).into_response())
+}
+
+async fn get_items(
+    Query(MultiGetParams { shape }): Query<MultiGetParams>,
+    State(state): State<AppState>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    let ndjson = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(NDJSON));
+    if ndjson {
+        if shape != MultiGetShape::List {
+            return Err(ApiError::bad_request(
+                "an NDJSON mget can only be answered as a list",
+            ));
+        }
+        return Ok(get_items_ndjson(state, request.into_body()));
+    }
+    let AppJson(keys) = AppJson::<Vec<String>>::from_request(request, &state).await?;
+    let result = match shape {
+        MultiGetShape::List => state
+            .db_client
+            .get_items(keys)
+            .await
+            .map(|items| Json(items).into_response()),
+        MultiGetShape::Map => state
+            .db_client
+            .get_items_map(keys)
+            .await
+            .map(|items| Json(items).into_response()),
+    };
+    result.map_err(|err| state.error(err))
+}
+
+const NDJSON: &str = "application/x-ndjson";
+
+// Keys per lookup in an NDJSON mget: enough to make the trip to the database
+// thread worthwhile, few enough that the first results go out soon.
+const NDJSON_MGET_BATCH: usize = 1000;
+
+// Longest line in an NDJSON mget body. Keys are fetched as soon as their line
+// ends, so this is all of the body that's ever held at once.
+const MAX_NDJSON_LINE: usize = 64 * 1024;
+
+// An mget whose body is a stream of JSON string keys, one per line, answered
+// with a stream of results, one line per key in the same order: the item, or
+// null where it's missing. Neither side is held in memory as a whole. If
+// something goes wrong partway, the last line is `{"error": "..."}`.
+fn get_items_ndjson(state: AppState, body: Body) -> Response {
+    let (lines_tx, lines_rx) = mpsc::channel::<Bytes>(4);
+    tokio::spawn(async move {
+        // The response outlives the handler, so the middleware's guard has
+        // already been dropped by the time most of this runs.
+        let _guard = state.activity.begin();
+        if let Err(message) = stream_mget(&state, body, &lines_tx).await {
+            let mut line = json!({ "error": message }).to_string();
+            line.push('\n');
+            let _ = lines_tx.send(Bytes::from(line)).await;
+        }
+    });
+    let lines = futures_util::stream::unfold(lines_rx, |mut lines_rx| async move {
+        let line = lines_rx.recv().await?;
+        Some((Ok::<_, std::convert::Infallible>(line), lines_rx))
+    });
+    ([(CONTENT_TYPE, NDJSON)], Body::from_stream(lines)).into_response()
+}
+
+async fn stream_mget(
+    state: &AppState,
+    body: Body,
+    lines_tx: &mpsc::Sender<Bytes>,
+) -> Result<(), String> {
+    let mut chunks = body.into_data_stream();
+    let mut pending = Vec::new();
+    let mut keys = Vec::new();
+    let mut line_number = 0;
+    loop {
+        let chunk = chunks
+            .next()
+            .await
+            .transpose()
+            .map_err(|err| format!("failed to read request body: {err}"))?;
+        let done = chunk.is_none();
+        match chunk {
+            Some(chunk) => pending.extend_from_slice(&chunk),
+            // The last line doesn't need a newline of its own.
+            None => pending.push(b'\n'),
+        }
+        while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            line_number += 1;
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let key = serde_json::from_slice::<String>(&line)
+                .map_err(|err| format!("invalid key on line {line_number}: {err}"))?;
+            keys.push(key);
+            if keys.len() == NDJSON_MGET_BATCH {
+                send_mget_batch(state, std::mem::take(&mut keys), lines_tx).await?;
+            }
+        }
+        if done {
+            break;
+        }
+        if pending.len() > MAX_NDJSON_LINE {
+            return Err(format!("lines are limited to {MAX_NDJSON_LINE} bytes"));
+        }
+    }
+    send_mget_batch(state, keys, lines_tx).await
+}
+
+async fn send_mget_batch(
+    state: &AppState,
+    keys: Vec<String>,
+    lines_tx: &mpsc::Sender<Bytes>,
+) -> Result<(), String> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let items = state
+        .db_client
+        .get_items(keys)
+        .await
+        .map_err(|err| state.error(err).message)?;
+    let mut lines = Vec::new();
+    for item in items {
+        serde_json::to_writer(&mut lines, &item).expect("items always serialize");
+        lines.push(b'\n');
+    }
+    lines_tx
+        .send(Bytes::from(lines))
+        .await
+        .map_err(|_| "the client went away".to_string())
+}
+
+// `value` is read as a JSON literal when it is one (`3`, `true`, `"3"`), and as
+// a plain string otherwise, so `?value=book` works without quotes.
+async fn get_by_index(
+    Path(name): Path<String>,
+    Query(IndexParams { value }): Query<IndexParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let value = serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value));
+    match state.db_client.get_by_index(name, value).await {
+        Ok(items) => Ok(Json(items)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn get_by_tag(
+    Path(tag): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.get_by_tag(tag).await {
+        Ok(items) => Ok(Json(items)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn get_tags(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.get_tags(key).await {
+        Ok(Some(tags)) => Ok(Json(tags)),
+        Ok(None) => Err(ApiError::not_found()),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn add_tag(
+    Path((key, tag)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, ApiError> {
+    match state.db_client.add_tag(key, tag).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(ApiError::not_found()),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn remove_tag(
+    Path((key, tag)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, ApiError> {
+    match state.db_client.remove_tag(key, tag).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(ApiError::not_found()),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn apply(
+    State(state): State<AppState>,
+    AppJson(ops): AppJson<Vec<Op>>,
+) -> Result<StatusCode, ApiError> {
+    match state.db_client.apply(ops).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn query(
+    State(state): State<AppState>,
+    AppJson(filter): AppJson<Filter>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.query(filter).await {
+        Ok(items) => Ok(Json(items)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn aggregate(
+    Query(AggregateParams { prefix, op }): Query<AggregateParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.aggregate(prefix.clone(), op.into()).await {
+        Ok(value) => Ok(Json(json!({ "prefix": prefix, "op": op, "value": value }))),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn sync_diff(
+    State(state): State<AppState>,
+    AppJson(DiffPayload {
+        prefix_len,
+        buckets,
+    }): AppJson<DiffPayload>,
+) -> Result<impl IntoResponse, ApiError> {
+    let client_buckets = buckets
+        .into_iter()
+        .map(|(bucket, hash)| match u64::from_str_radix(&hash, 16) {
+            Ok(hash) => Ok((bucket, hash)),
+            Err(_) => Err(ApiError::bad_request(format!(
+                "hash for bucket {bucket:?} is not hex: {hash:?}"
+            ))),
+        })
+        .collect::<Result<_, _>>()?;
+    match state.db_client.diff(prefix_len, client_buckets).await {
+        Ok(differing) => {
+            let differing: BTreeMap<_, _> = differing
+                .into_iter()
+                .map(|(bucket, hash)| (bucket, hash.map(|hash| format!("{hash:016x}"))))
+                .collect();
+            Ok(Json(json!({ "buckets": differing })))
+        }
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn get_keys(
+    Query(KeysParams { with_size }): Query<KeysParams>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    let result = if with_size {
+        state
+            .db_client
+            .get_key_sizes()
+            .await
+            .map(|sizes| Json(sizes).into_response())
+    } else {
+        state
+            .db_client
+            .get_keys()
+            .await
+            .map(|keys| Json(keys).into_response())
+    };
+    result.map_err(|err| state.error(err))
+}
+
+async fn count_items(
+    Query(CountParams { estimate }): Query<CountParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let result = if estimate {
+        state.db_client.count_estimate().await
+    } else {
+        state.db_client.count().await
+    };
+    match result {
+        Ok(count) => Ok(Json(json!({ "count": count }))),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn item_exists(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, ApiError> {
+    match state.db_client.exists(key).await {
+        Ok(true) => Ok(StatusCode::OK),
+        Ok(false) => Ok(StatusCode::NOT_FOUND),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn get_read_count(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.get_read_count(key.clone()).await {
+        Ok(reads) => Ok(Json(json!({ "key": key, "reads": reads }))),
+        Err(err) => Err(state.error(err)),
     }
 }
 
 async fn put_item(
     Path(key): Path<String>,
-    State(db_client): State<DatabaseClient>,
-    Json(ValuePayload { value }): Json<ValuePayload>,
-) -> Result<impl IntoResponse, StatusCode> {
-    match db_client.put_item(Item { key, value }).await {
-        Ok(_) => Ok(StatusCode::CREATED),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    Query(PutParams {
+        expand,
+        if_changed,
+        durable,
+        returning,
+    }): Query<PutParams>,
+    State(state): State<AppState>,
+    AppJson(ValuePayload { value }): AppJson<ValuePayload>,
+) -> Result<Response, ApiError> {
+    let value = if expand { expand_tokens(&value) } else { value };
+    let truncated = state.truncated_header(&value);
+    let item = Item { key, value };
+    if durable && if_changed {
+        return Err(ApiError::bad_request(
+            "durable can't be combined with if_changed",
+        ));
+    }
+    if returning == Returning::Representation {
+        return match state
+            .db_client
+            .put_item_returning(item, durable, if_changed)
+            .await
+        {
+            Ok((stored, _)) => Ok((StatusCode::OK, truncated, Json(stored)).into_response()),
+            Err(err) => Err(state.error(err)),
+        };
+    }
+    let written = |status: StatusCode| (status, truncated, ()).into_response();
+    if durable {
+        return match state.db_client.put_item_durable(item).await {
+            Ok(()) => Ok(written(StatusCode::CREATED)),
+            Err(err) => Err(state.error(err)),
+        };
+    }
+    if if_changed {
+        // 200 rather than 201 tells the client the write was a no-op.
+        return match state.db_client.put_item_if_changed(item).await {
+            Ok(true) => Ok(written(StatusCode::CREATED)),
+            Ok(false) => Ok(written(StatusCode::OK)),
+            Err(err) => Err(state.error(err)),
+        };
+    }
+    match state.db_client.put_item(item).await {
+        Ok(_) => Ok(written(StatusCode::CREATED)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+// Binary keys travel in the path as unpadded base64url, and are echoed back the
+// same way.
+fn decode_bin_key(key: &str) -> Result<Vec<u8>, ApiError> {
+    URL_SAFE_NO_PAD
+        .decode(key)
+        .map_err(|err| ApiError::bad_request(format!("key is not valid base64url: {err}")))
+}
+
+// The value as the whole body, for clients that don't want to wrap it in
+// JSON. It still has to be UTF-8 text: SQLite would store other bytes in the
+// TEXT column without complaint, and they'd then fail to read back.
+async fn put_item_raw(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+    body: Bytes,
+) -> Result<impl IntoResponse, ApiError> {
+    let value = String::from_utf8(body.to_vec()).map_err(|err| {
+        ApiError::bad_request(format!(
+            "body is not valid UTF-8 (invalid byte at offset {})",
+            err.utf8_error().valid_up_to()
+        ))
+    })?;
+    let truncated = state.truncated_header(&value);
+    match state.db_client.put_item(Item { key, value }).await {
+        Ok(()) => Ok((StatusCode::CREATED, truncated, ())),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn get_item_raw(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let range = headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_byte_range);
+    let Some(range) = range else {
+        return match state.db_client.get_item(key).await {
+            Ok(Some(item)) => Ok((
+                [
+                    (CONTENT_TYPE, "text/plain; charset=utf-8"),
+                    (ACCEPT_RANGES, "bytes"),
+                ],
+                item.value,
+            )
+                .into_response()),
+            Ok(None) => Err(ApiError::not_found()),
+            Err(err) => Err(state.error(err)),
+        };
+    };
+    match state.db_client.get_value_range(key, range).await {
+        Ok(Some(slice)) => Ok((
+            StatusCode::PARTIAL_CONTENT,
+            [(CONTENT_TYPE, "text/plain; charset=utf-8")],
+            [(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", slice.start, slice.end, slice.total),
+            )],
+            slice.bytes,
+        )
+            .into_response()),
+        Ok(None) => Err(ApiError::not_found()),
+        Err(err) => match err.downcast_ref::<RangeNotSatisfiable>() {
+            Some(RangeNotSatisfiable { total }) => Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(CONTENT_RANGE, format!("bytes */{total}"))],
+            )
+                .into_response()),
+            None => Err(state.error(err)),
+        },
+    }
+}
+
+// The one range in a `Range: bytes=...` header. Anything else (another unit,
+// several ranges, or a header we can't parse) is ignored, and the whole value
+// is sent, as RFC 9110 allows.
+fn parse_byte_range(header: &str) -> Option<ByteRange> {
+    let spec = header.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+    match (start, end) {
+        ("", last) => Some(ByteRange::Last(last.parse().ok()?)),
+        (start, "") => Some(ByteRange::From(start.parse().ok()?)),
+        (start, end) => {
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            (start <= end).then_some(ByteRange::FromTo(start, end))
+        }
+    }
+}
+
+async fn get_item_bin_key(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state
+        .db_client
+        .get_item_bin_key(decode_bin_key(&key)?)
+        .await
+    {
+        Ok(Some(value)) => Ok(Json(Item { key, value })),
+        Ok(None) => Err(ApiError::not_found()),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn put_item_bin_key(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+    AppJson(ValuePayload { value }): AppJson<ValuePayload>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state
+        .db_client
+        .put_item_bin_key(decode_bin_key(&key)?, value)
+        .await
+    {
+        Ok(()) => Ok(StatusCode::CREATED),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn get_all_attached_items(
+    Path(alias): Path<String>,
+    Query(DecodeParams { decode_json }): Query<DecodeParams>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    match state.db_client.get_all_attached_items(alias).await {
+        Ok(items) => items_response(items, decode_json),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn get_attached_item(
+    Path((alias, key)): Path<(String, String)>,
+    Query(DecodeParams { decode_json }): Query<DecodeParams>,
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    match state.db_client.get_attached_item(alias, key).await {
+        Ok(Some(item)) => item_response(item, decode_json),
+        Ok(None) => Err(ApiError::not_found()),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn pop_item(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.pop(key).await {
+        Ok(Some(item)) => Ok(Json(item)),
+        Ok(None) => Err(ApiError::not_found()),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+#[derive(Deserialize)]
+struct LeaseRequest {
+    candidate_id: String,
+    ttl_ms: u64,
+}
+
+// The longest lease a candidate can ask for. A leader that wants to keep the
+// lease for longer renews it.
+const MAX_LEASE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn lease_ttl(ttl_ms: u64) -> Result<Duration, ApiError> {
+    let ttl = Duration::from_millis(ttl_ms);
+    if ttl.is_zero() || ttl > MAX_LEASE_TTL {
+        return Err(ApiError::bad_request(format!(
+            "ttl_ms must be between 1 and {}",
+            MAX_LEASE_TTL.as_millis()
+        )));
+    }
+    Ok(ttl)
+}
+
+// POST takes the lease if it's free (or already ours); PUT only extends one
+// that's still held. Either way the answer says who leads now, with `held`
+// telling the caller whether it's them.
+async fn acquire_leadership(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+    AppJson(LeaseRequest {
+        candidate_id,
+        ttl_ms,
+    }): AppJson<LeaseRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let ttl = lease_ttl(ttl_ms)?;
+    match state
+        .db_client
+        .acquire_leadership(key, candidate_id, ttl)
+        .await
+    {
+        Ok(leadership) => Ok(Json(leadership)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn renew_leadership(
+    Path(key): Path<String>,
+    State(state): State<AppState>,
+    AppJson(LeaseRequest {
+        candidate_id,
+        ttl_ms,
+    }): AppJson<LeaseRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let ttl = lease_ttl(ttl_ms)?;
+    match state
+        .db_client
+        .renew_leadership(key, candidate_id, ttl)
+        .await
+    {
+        Ok(leadership) => Ok(Json(leadership)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+// Blobs are ordinary items, stored under this prefix and the hex SHA-256 of
+// their contents, so the same contents are only ever stored once. Being
+// ordinary items, they can also be written through the item routes, so they
+// are checked against their hash on the way out.
+const BLOB_KEY_PREFIX: &str = "sha256:";
+
+fn blob_hash(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+async fn put_blob(State(state): State<AppState>, body: Bytes) -> Result<Response, ApiError> {
+    let value = String::from_utf8(body.to_vec()).map_err(|err| {
+        ApiError::bad_request(format!(
+            "body is not valid UTF-8 (invalid byte at offset {})",
+            err.utf8_error().valid_up_to()
+        ))
+    })?;
+    // A blob cut short would no longer match its hash.
+    if state.truncated_header(&value).is_some() {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "blob is longer than --max-value-bytes",
+        ));
+    }
+    let hash = blob_hash(&value);
+    let item = Item {
+        key: format!("{BLOB_KEY_PREFIX}{hash}"),
+        value,
+    };
+    // 201 if this stored it, 200 if it was already there.
+    let status = match state.db_client.put_items_if_absent(vec![item]).await {
+        Ok(0) => StatusCode::OK,
+        Ok(_) => StatusCode::CREATED,
+        Err(err) => return Err(state.error(err)),
+    };
+    Ok((status, Json(json!({ "hash": hash }))).into_response())
+}
+
+async fn get_blob(
+    Path(hash): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state
+        .db_client
+        .get_item(format!("{BLOB_KEY_PREFIX}{hash}"))
+        .await
+    {
+        Ok(Some(item)) if blob_hash(&item.value) == hash => {
+            Ok(([(CONTENT_TYPE, "text/plain; charset=utf-8")], item.value))
+        }
+        // Written some other way, with contents that don't match the key.
+        Ok(Some(item)) => {
+            tracing::warn!(key = item.key, "blob doesn't match its hash");
+            Err(ApiError::not_found())
+        }
+        Ok(None) => Err(ApiError::not_found()),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+#[derive(Deserialize)]
+struct WindowParams {
+    at: String,
+    #[serde(default = "default_window_count")]
+    count: u32,
+    #[serde(default)]
+    dir: WindowDirection,
+}
+
+fn default_window_count() -> u32 {
+    DEFAULT_PAGE_SIZE
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum WindowDirection {
+    #[default]
+    Forward,
+    Backward,
+}
+
+impl From<WindowDirection> for Direction {
+    fn from(direction: WindowDirection) -> Self {
+        match direction {
+            WindowDirection::Forward => Direction::Forward,
+            WindowDirection::Backward => Direction::Backward,
+        }
+    }
+}
+
+async fn get_window(
+    Query(WindowParams { at, count, dir }): Query<WindowParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.get_window(at, count, dir.into()).await {
+        Ok(items) => Ok(Json(items)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+#[derive(Deserialize)]
+struct DecrementParams {
+    #[serde(default = "default_delta")]
+    delta: i64,
+    #[serde(default)]
+    floor: i64,
+}
+
+fn default_delta() -> i64 {
+    1
+}
+
+async fn decrement_item(
+    Path(key): Path<String>,
+    Query(DecrementParams { delta, floor }): Query<DecrementParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    if delta < 0 {
+        return Err(ApiError::bad_request("delta can't be negative"));
+    }
+    match state.db_client.decrement_floor(key, delta, floor).await {
+        Ok((value, floor_hit)) => Ok(Json(json!({ "value": value, "floor_hit": floor_hit }))),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+#[derive(Deserialize)]
+struct ArchiveParams {
+    to_prefix: String,
+}
+
+async fn archive_item(
+    Path(key): Path<String>,
+    Query(ArchiveParams { to_prefix }): Query<ArchiveParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.archive(key, to_prefix).await {
+        Ok(Some(item)) => Ok(Json(item)),
+        Ok(None) => Err(ApiError::not_found()),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn delete_range(
+    Query(RangeParams { start, end }): Query<RangeParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.delete_range(start, end).await {
+        Ok(deleted) => Ok(Json(json!({ "deleted": deleted }))),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn top_by_size(
+    Query(TopParams { n }): Query<TopParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.top_by_size(n).await {
+        Ok(sizes) => Ok(Json(sizes)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+#[derive(Deserialize)]
+struct DuplicatesParams {
+    #[serde(default)]
+    min: u32,
+}
+
+async fn find_duplicates(
+    Query(DuplicatesParams { min }): Query<DuplicatesParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.find_duplicates(min).await {
+        Ok(duplicates) => Ok(Json(duplicates)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn flush(State(state): State<AppState>) -> Result<StatusCode, ApiError> {
+    match state.db_client.flush().await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn get_schema(State(state): State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.get_schema().await {
+        Ok(schema) => Ok(Json(schema)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+async fn explain(
+    Query(params): Query<ExplainParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let query = match (params.op, params.prefix, params.start, params.end) {
+        (ExplainOp::Scan, ..) => Explain::Scan,
+        (ExplainOp::Prefix, Some(prefix), ..) => Explain::Query(Filter::PrefixEq(prefix)),
+        (ExplainOp::Range, _, Some(start), Some(end)) => Explain::Range { start, end },
+        (ExplainOp::Prefix, ..) => return Err(ApiError::bad_request("op=prefix needs prefix")),
+        (ExplainOp::Range, ..) => {
+            return Err(ApiError::bad_request("op=range needs start and end"));
+        }
+    };
+    match state.db_client.explain(query).await {
+        Ok(plan) => Ok(Json(plan)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+// Answered from the client's side of the queue, so it works even when the
+// database thread is wedged.
+async fn inflight(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.db_client.queued())
+}
+
+async fn diagnostics(State(state): State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    match state.db_client.diagnostics().await {
+        Ok(diagnostics) => Ok(Json(diagnostics)),
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+// Prometheus text format.
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let cache = state.db_client.cache_stats();
+    let body = format!(
+        "# TYPE bgdb_cache_hits_total counter\n\
+         bgdb_cache_hits_total {}\n\
+         # TYPE bgdb_cache_misses_total counter\n\
+         bgdb_cache_misses_total {}\n",
+        cache.hits, cache.misses,
+    );
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+// Longest path segment (i.e. key, for most routes), as sent, still
+// percent-encoded. Anything longer is turned away before routing.
+const MAX_PATH_SEGMENT_LEN: usize = 4096;
+
+async fn limit_path_segments(request: Request, next: Next) -> Response {
+    if request
+        .uri()
+        .path()
+        .split('/')
+        .any(|segment| segment.len() > MAX_PATH_SEGMENT_LEN)
+    {
+        return ApiError::new(
+            StatusCode::URI_TOO_LONG,
+            format!("path segments are limited to {MAX_PATH_SEGMENT_LEN} bytes"),
+        )
+        .into_response();
+    }
+    next.run(request).await
+}
+
+// Gives up on a handler that runs past `timeout`. Whatever it was waiting on
+// is dropped, though a request already handed to the database thread still
+// runs there. Only the handler is timed: a body that streams out afterwards
+// (e.g. an NDJSON mget) can take as long as it takes.
+async fn response_timeout(
+    State(timeout): State<Duration>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("no response within {}s", timeout.as_secs()),
+        )
+        .into_response(),
+    }
+}
+
+async fn require_admin_token(
+    State(token): State<Arc<str>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+    if !authorized {
+        return ApiError::new(StatusCode::UNAUTHORIZED, "missing or wrong admin token")
+            .into_response();
+    }
+    next.run(request).await
+}
+
+// Takes the same time however much of `a` matches `b`, so timing a guess at
+// the token doesn't tell how close it was. Only the length can leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn put_items(
+    Query(BatchParams { best_effort }): Query<BatchParams>,
+    State(state): State<AppState>,
+    AppJson(items): AppJson<Vec<Item>>,
+) -> Result<Response, ApiError> {
+    if !best_effort {
+        return match state.db_client.put_items(items).await {
+            Ok(()) => Ok(StatusCode::CREATED.into_response()),
+            Err(err) => Err(state.error(err)),
+        };
+    }
+    match state.db_client.put_items_best_effort(items).await {
+        Ok(results) => {
+            let results: Vec<_> = results
+                .into_iter()
+                .map(|result| match result {
+                    Ok(()) => json!({ "ok": true }),
+                    Err(error) => json!({ "ok": false, "error": error }),
+                })
+                .collect();
+            Ok(Json(results).into_response())
+        }
+        Err(err) => Err(state.error(err)),
+    }
+}
+
+// SQLite only accepts paths rooted at `$`; catch the rest here so they're a
+// 400 rather than an opaque query failure.
+fn check_json_path(path: &str) -> Result<(), ApiError> {
+    if path.starts_with('$') {
+        Ok(())
+    } else {
+        Err(ApiError::bad_request(format!(
+            "value_json_path must start with '$', got {path:?}"
+        )))
+    }
+}
+
+// Replaces the tokens understood by `?expand=true`. The set is deliberately
+// small and fixed; anything else that looks like `${...}` is stored as-is.
+//   ${now}  -> milliseconds since the unix epoch, the same for the whole value
+//   ${uuid} -> a fresh random (v4) UUID for every occurrence
+fn expand_tokens(value: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        .to_string();
+    let value = value.replace("${now}", &now);
+
+    let mut parts = value.split("${uuid}");
+    let mut expanded = parts.next().unwrap_or_default().to_owned();
+    for part in parts {
+        expanded.push_str(&Uuid::new_v4().to_string());
+        expanded.push_str(part);
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_range_forms() {
+        assert_eq!(parse_byte_range("bytes=0-0"), Some(ByteRange::FromTo(0, 0)));
+        assert_eq!(
+            parse_byte_range("bytes=2-99"),
+            Some(ByteRange::FromTo(2, 99))
+        );
+        assert_eq!(parse_byte_range("bytes=5-"), Some(ByteRange::From(5)));
+        assert_eq!(parse_byte_range("bytes=-0"), Some(ByteRange::Last(0)));
+        assert_eq!(parse_byte_range("bytes=-10"), Some(ByteRange::Last(10)));
+    }
+
+    #[test]
+    fn byte_range_ignores_several_ranges() {
+        assert_eq!(parse_byte_range("bytes=0-1,3-4"), None);
+        assert_eq!(parse_byte_range("bytes=0-1, -2"), None);
+    }
+
+    #[test]
+    fn byte_range_ignores_junk() {
+        for header in [
+            "",
+            "bytes=",
+            "bytes=-",
+            "bytes=abc",
+            "bytes=1-x",
+            "bytes=1-2-3",
+            "bytes=2-1",
+            "bytes=-1-2",
+            "items=0-1",
+        ] {
+            assert_eq!(parse_byte_range(header), None, "{header:?}");
+        }
+    }
+
+    fn is_millis(s: &str) -> bool {
+        !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+    }
+
+    #[test]
+    fn expands_now_once_per_value() {
+        let expanded = expand_tokens("${now}/${now}");
+        let (a, b) = expanded.split_once('/').unwrap();
+        assert!(is_millis(a), "{expanded}");
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn expands_each_uuid_separately() {
+        let expanded = expand_tokens("${uuid} ${uuid}");
+        let (a, b) = expanded.split_once(' ').unwrap();
+        assert!(Uuid::parse_str(a).is_ok(), "{expanded}");
+        assert!(Uuid::parse_str(b).is_ok(), "{expanded}");
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn leaves_unknown_and_unterminated_tokens() {
+        for value in ["${nope}", "${now", "${uuid", "$now", "{now}", "${NOW}", ""] {
+            assert_eq!(expand_tokens(value), value);
+        }
+    }
+
+    // There's no escape syntax: a token is expanded whatever comes before it.
+    #[test]
+    fn has_no_escapes() {
+        let expanded = expand_tokens("$${now}");
+        assert!(is_millis(expanded.strip_prefix('$').unwrap()), "{expanded}");
+        let expanded = expand_tokens("\\${now}");
+        assert!(
+            is_millis(expanded.strip_prefix('\\').unwrap()),
+            "{expanded}"
+        );
+    }
+
+    #[test]
+    fn lease_ttl_bounds() {
+        assert!(lease_ttl(0).is_err());
+        assert_eq!(lease_ttl(1).ok(), Some(Duration::from_millis(1)));
+        let day = MAX_LEASE_TTL.as_millis() as u64;
+        assert_eq!(lease_ttl(day).ok(), Some(MAX_LEASE_TTL));
+        assert!(lease_ttl(day + 1).is_err());
+        assert!(lease_ttl(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"Secret"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(!constant_time_eq(b"secret", b"secre"));
+        assert!(!constant_time_eq(b"", b"secret"));
+        assert!(constant_time_eq(b"", b""));
     }
 }
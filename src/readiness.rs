@@ -0,0 +1,57 @@
+//! Whether the server is ready to serve requests yet. The listener is bound
+//! before the database has been warmed up, so that deploy tooling can tell a
+//! server that's still starting (503 from `/readyz`) from one that's down.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::ApiError;
+
+#[derive(Clone, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn set_ready(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+fn not_ready() -> Response {
+    let mut response = ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "starting up, not ready yet",
+    )
+    .into_response();
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+    response
+}
+
+/// Turns requests away with a 503 until the server is ready.
+pub async fn gate(State(readiness): State<Readiness>, request: Request, next: Next) -> Response {
+    if !readiness.is_ready() {
+        return not_ready();
+    }
+    next.run(request).await
+}
+
+pub async fn readyz(State(readiness): State<Readiness>) -> Response {
+    if !readiness.is_ready() {
+        return not_ready();
+    }
+    StatusCode::OK.into_response()
+}
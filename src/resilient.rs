@@ -0,0 +1,95 @@
+//! A [`DatabaseClient`] that outlives its database thread. If the thread is
+//! gone (it gave up after too many reopens, or panicked past its guards), the
+//! next request to notice opens the database again, spawns a new thread, and
+//! retries on it.
+//!
+//! Only requests that never reached the old thread are retried, i.e. the ones
+//! that failed with [`ShuttingDown`]. A request the thread took but never
+//! answered may or may not have taken effect, so its error is passed on as-is
+//! rather than risking doing it twice.
+
+use std::{
+    future::Future,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use tokio::sync::Mutex;
+
+use crate::backgroundb::{
+    open_with_options, spawn_with_options, DatabaseClient, OpenOptions, ShuttingDown, SpawnOptions,
+};
+
+pub struct ResilientClient {
+    path: PathBuf,
+    open_options: OpenOptions,
+    spawn_options: SpawnOptions,
+    // The current client, and how many times it has been replaced, so that
+    // callers who all saw the same client die only replace it once.
+    current: Mutex<(u64, DatabaseClient)>,
+    // Set by `shutdown`, after which a dead thread is expected.
+    closed: AtomicBool,
+}
+
+impl ResilientClient {
+    /// Opens the database at `path` and spawns its thread, as
+    /// [`open_with_options`] and [`spawn_with_options`] would, remembering
+    /// the options to do it again later.
+    pub fn open(
+        path: PathBuf,
+        open_options: OpenOptions,
+        spawn_options: SpawnOptions,
+    ) -> anyhow::Result<Self> {
+        let conn = open_with_options(path.clone(), open_options.clone())?;
+        let client = spawn_with_options(conn, spawn_options.clone());
+        Ok(Self {
+            path,
+            open_options,
+            spawn_options,
+            current: Mutex::new((0, client)),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Runs `f` against the current client, respawning the database thread
+    /// and running it once more if the thread turns out to be gone, e.g.
+    /// `db.run(|client| client.get_item(key.clone())).await`.
+    pub async fn run<T, F, Fut>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: Fn(DatabaseClient) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let (generation, client) = self.current.lock().await.clone();
+        match f(client).await {
+            Err(err) if err.is::<ShuttingDown>() && !self.closed.load(Ordering::SeqCst) => {
+                let client = self.respawn(generation).await?;
+                f(client).await
+            }
+            result => result,
+        }
+    }
+
+    /// Shuts the database thread down for good: later requests fail with
+    /// [`ShuttingDown`] instead of bringing it back.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        let client = self.current.lock().await.1.clone();
+        client.shutdown().await
+    }
+
+    async fn respawn(&self, dead: u64) -> anyhow::Result<DatabaseClient> {
+        let mut current = self.current.lock().await;
+        if current.0 == dead {
+            tracing::warn!("database thread is gone, respawning it");
+            let path = self.path.clone();
+            let open_options = self.open_options.clone();
+            let conn = tokio::task::spawn_blocking(move || open_with_options(path, open_options))
+                .await??;
+            *current = (
+                dead + 1,
+                spawn_with_options(conn, self.spawn_options.clone()),
+            );
+        }
+        Ok(current.1.clone())
+    }
+}
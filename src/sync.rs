@@ -0,0 +1,168 @@
+//! Bucket hashes for syncing a copy of the store without re-sending what's
+//! unchanged. Keys are grouped into buckets by their first `prefix_len`
+//! characters, and each bucket gets a hash of its contents. A client computes
+//! the same hashes over its own copy, sends them, and gets back the buckets
+//! that differ, which it can then re-fetch.
+//!
+//! The hash of an item is 64-bit FNV-1a over the key's bytes, a 0xff byte
+//! (which never occurs in UTF-8, so it can't be confused with key or value
+//! bytes), and the value's bytes. A bucket's hash is the wrapping sum of the
+//! hashes of its items, so it doesn't depend on the order they're visited in.
+
+use std::collections::{BTreeMap, HashMap};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+pub fn item_hash(key: &str, value: &str) -> u64 {
+    let hash = fnv1a(FNV_OFFSET_BASIS, key.as_bytes());
+    let hash = fnv1a(hash, &[0xff]);
+    fnv1a(hash, value.as_bytes())
+}
+
+/// The bucket `key` falls in: its first `prefix_len` characters, or all of
+/// it if it's shorter.
+pub fn bucket_of(key: &str, prefix_len: usize) -> &str {
+    match key.char_indices().nth(prefix_len) {
+        Some((end, _)) => &key[..end],
+        None => key,
+    }
+}
+
+/// Accumulates bucket hashes one item at a time.
+pub struct BucketHasher {
+    prefix_len: usize,
+    buckets: HashMap<String, u64>,
+}
+
+impl BucketHasher {
+    pub fn new(prefix_len: usize) -> Self {
+        Self {
+            prefix_len,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: &str, value: &str) {
+        let hash = item_hash(key, value);
+        let bucket = bucket_of(key, self.prefix_len);
+        match self.buckets.get_mut(bucket) {
+            Some(sum) => *sum = sum.wrapping_add(hash),
+            None => {
+                self.buckets.insert(bucket.to_owned(), hash);
+            }
+        }
+    }
+
+    pub fn finish(self) -> HashMap<String, u64> {
+        self.buckets
+    }
+}
+
+/// The buckets where `ours` and `theirs` disagree, with our hash for each, or
+/// `None` where we have nothing in that bucket at all.
+pub fn diff(
+    ours: &HashMap<String, u64>,
+    theirs: &HashMap<String, u64>,
+) -> BTreeMap<String, Option<u64>> {
+    let mut differing = BTreeMap::new();
+    for (bucket, hash) in ours {
+        if theirs.get(bucket) != Some(hash) {
+            differing.insert(bucket.clone(), Some(*hash));
+        }
+    }
+    for bucket in theirs.keys() {
+        if !ours.contains_key(bucket) {
+            differing.insert(bucket.clone(), None);
+        }
+    }
+    differing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(prefix_len: usize, items: &[(&str, &str)]) -> HashMap<String, u64> {
+        let mut hasher = BucketHasher::new(prefix_len);
+        for (key, value) in items {
+            hasher.add(key, value);
+        }
+        hasher.finish()
+    }
+
+    const ITEMS: &[(&str, &str)] = &[("apple", "1"), ("apricot", "2"), ("banana", "3")];
+
+    #[test]
+    fn bucket_is_the_first_chars() {
+        assert_eq!(bucket_of("apple", 2), "ap");
+        assert_eq!(bucket_of("a", 2), "a");
+        assert_eq!(bucket_of("", 2), "");
+        assert_eq!(bucket_of("éclair", 1), "é");
+        assert_eq!(bucket_of("apple", 0), "");
+    }
+
+    #[test]
+    fn identical_sets_have_no_diff() {
+        let ours = hashes(2, ITEMS);
+        let theirs = hashes(2, ITEMS);
+        assert!(diff(&ours, &theirs).is_empty());
+    }
+
+    #[test]
+    fn hashes_are_stable_across_order_and_calls() {
+        let reversed: Vec<_> = ITEMS.iter().rev().copied().collect();
+        assert_eq!(hashes(2, ITEMS), hashes(2, &reversed));
+        assert_eq!(hashes(2, ITEMS), hashes(2, ITEMS));
+    }
+
+    // Clients compute the same hashes on their side, so they can't depend on
+    // anything but the items, e.g. a per-process seed.
+    #[test]
+    fn item_hash_is_plain_fnv1a() {
+        assert_eq!(item_hash("", ""), 0xaf64724c8602eb6e);
+    }
+
+    #[test]
+    fn key_only_we_have() {
+        let ours = hashes(2, ITEMS);
+        let theirs = hashes(2, &ITEMS[..2]);
+        let differing = diff(&ours, &theirs);
+        assert_eq!(differing.len(), 1);
+        assert_eq!(differing["ba"], Some(ours["ba"]));
+    }
+
+    #[test]
+    fn key_only_they_have() {
+        let ours = hashes(2, &ITEMS[..2]);
+        let theirs = hashes(2, ITEMS);
+        let differing = diff(&ours, &theirs);
+        assert_eq!(differing.len(), 1);
+        assert_eq!(differing["ba"], None);
+    }
+
+    #[test]
+    fn changed_value_marks_only_its_bucket() {
+        let ours = hashes(2, ITEMS);
+        let theirs = hashes(
+            2,
+            &[("apple", "1"), ("apricot", "changed"), ("banana", "3")],
+        );
+        let differing = diff(&ours, &theirs);
+        assert_eq!(differing.len(), 1);
+        assert_eq!(differing["ap"], Some(ours["ap"]));
+    }
+
+    #[test]
+    fn key_and_value_boundary_matters() {
+        assert_ne!(item_hash("ab", "c"), item_hash("a", "bc"));
+    }
+}
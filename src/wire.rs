@@ -0,0 +1,168 @@
+//! A compact binary protocol over plain TCP, for clients that find HTTP and
+//! JSON too heavy. Every message in either direction is a frame: a 4-byte
+//! big-endian length followed by that many bytes of bincode (with bincode's
+//! default options, i.e. varint integers). Clients send `WireRequest`s and get
+//! back exactly one `WireResponse` for each, carrying the same `id`. As with
+//! the WebSocket protocol, requests are handled concurrently, so responses can
+//! arrive in a different order than the requests were sent.
+
+use std::sync::Arc;
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use sqlite_async::Item;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{mpsc, Semaphore},
+};
+
+use crate::AppState;
+
+// Bigger request frames are refused, and the connection dropped; this also
+// bounds what decoding one can allocate. Responses aren't limited.
+const MAX_FRAME: u32 = 16 << 20;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WireRequest {
+    pub id: u64,
+    pub op: WireOp,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WireOp {
+    Get { key: String },
+    GetItems { keys: Vec<String> },
+    GetAll,
+    Exists { key: String },
+    Put { item: Item },
+    PutItems { items: Vec<Item> },
+    Pop { key: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WireResponse {
+    /// `None` when the request couldn't be decoded far enough to tell.
+    pub id: Option<u64>,
+    pub result: Result<WireReply, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WireReply {
+    Item(Option<Item>),
+    Items(Vec<Item>),
+    MaybeItems(Vec<Option<Item>>),
+    Bool(bool),
+    Done,
+}
+
+pub async fn serve(listener: TcpListener, state: AppState) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(connection(stream, state.clone()));
+            }
+            Err(err) => tracing::warn!(%err, "failed to accept a wire connection"),
+        }
+    }
+}
+
+async fn connection(stream: TcpStream, state: AppState) {
+    let _ = stream.set_nodelay(true);
+    let (mut reader, mut writer) = stream.into_split();
+    let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<u8>>(64);
+    let writer_task = tokio::spawn(async move {
+        while let Some(frame) = reply_rx.recv().await {
+            if write_frame(&mut writer, &frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    // Each request holds a permit until its reply is queued, so a client that
+    // sends faster than we answer stops being read from, and TCP flow control
+    // pushes back on it.
+    let in_flight = Arc::new(Semaphore::new(state.max_in_flight_per_connection));
+    loop {
+        let permit = in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let frame = match read_frame(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(err) => {
+                tracing::debug!(%err, "dropping wire connection");
+                break;
+            }
+        };
+        let state = state.clone();
+        let reply_tx = reply_tx.clone();
+        tokio::spawn(async move {
+            let _ = reply_tx.send(handle(&state, &frame).await).await;
+            drop(permit);
+        });
+    }
+    // Let the writer finish sending replies that are still on their way.
+    drop(reply_tx);
+    let _ = writer_task.await;
+}
+
+// Returns None on a clean end of stream between frames.
+async fn read_frame(reader: &mut OwnedReadHalf) -> std::io::Result<Option<Vec<u8>>> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if len > MAX_FRAME {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes is over the limit of {MAX_FRAME}"),
+        ));
+    }
+    let mut frame = vec![0; len as usize];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+async fn write_frame(writer: &mut OwnedWriteHalf, frame: &[u8]) -> std::io::Result<()> {
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await
+}
+
+async fn handle(state: &AppState, frame: &[u8]) -> Vec<u8> {
+    // Like WebSocket messages, these don't pass through the HTTP middleware.
+    let _guard = state.activity.begin();
+    let decoder = bincode::DefaultOptions::new().with_limit(u64::from(MAX_FRAME));
+    let response = match decoder.deserialize::<WireRequest>(frame) {
+        Ok(WireRequest { id, op }) => WireResponse {
+            id: Some(id),
+            result: run(state, op).await,
+        },
+        Err(err) => WireResponse {
+            id: None,
+            result: Err(format!("invalid request: {err}")),
+        },
+    };
+    bincode::DefaultOptions::new()
+        .serialize(&response)
+        .expect("serializing into a Vec can't fail")
+}
+
+async fn run(state: &AppState, op: WireOp) -> Result<WireReply, String> {
+    let db = &state.db_client;
+    let result = match op {
+        WireOp::Get { key } => db.get_item(key).await.map(WireReply::Item),
+        WireOp::GetItems { keys } => db.get_items(keys).await.map(WireReply::MaybeItems),
+        WireOp::GetAll => db.get_all_items().await.map(WireReply::Items),
+        WireOp::Exists { key } => db.exists(key).await.map(WireReply::Bool),
+        WireOp::Put { item } => db.put_item(item).await.map(|()| WireReply::Done),
+        WireOp::PutItems { items } => db.put_items(items).await.map(|()| WireReply::Done),
+        WireOp::Pop { key } => db.pop(key).await.map(WireReply::Item),
+    };
+    result.map_err(|err| state.error(err).message)
+}
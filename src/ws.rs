@@ -0,0 +1,103 @@
+//! A small JSON request/response protocol over a WebSocket, for clients that
+//! want to pipeline many operations over one connection. Each text message is
+//! one request, e.g. `{"id": 1, "op": "get", "key": "x"}`, and gets exactly one
+//! reply carrying the same `id`: `{"id": 1, "result": ...}` or
+//! `{"id": 1, "error": "..."}`. Requests are handled concurrently, so replies
+//! can arrive in a different order than the requests were sent.
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::Response,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlite_async::Item;
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::AppState;
+
+#[derive(Deserialize)]
+struct WsRequest {
+    id: Value,
+    #[serde(flatten)]
+    op: WsOp,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WsOp {
+    Get { key: String },
+    GetAll,
+    Put { key: String, value: String },
+}
+
+pub async fn upgrade(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
+    ws.on_upgrade(move |socket| serve(socket, state))
+}
+
+async fn serve(mut socket: WebSocket, state: AppState) {
+    let (reply_tx, mut reply_rx) = mpsc::channel::<Value>(64);
+    // Each request holds a permit until its reply is queued, and the socket
+    // is only read from with a permit in hand, so a client that sends faster
+    // than we answer gets pushed back on. Replies keep flowing meanwhile.
+    let in_flight = Arc::new(Semaphore::new(state.max_in_flight_per_connection));
+    let mut permit = None;
+    loop {
+        tokio::select! {
+            acquired = in_flight.clone().acquire_owned(), if permit.is_none() => {
+                permit = Some(acquired.expect("the semaphore is never closed"));
+            }
+            message = socket.recv(), if permit.is_some() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let state = state.clone();
+                let reply_tx = reply_tx.clone();
+                let permit = permit.take();
+                tokio::spawn(async move {
+                    let _ = reply_tx.send(handle(&state, &text).await).await;
+                    drop(permit);
+                });
+            }
+            Some(reply) = reply_rx.recv() => {
+                if socket.send(Message::Text(reply.to_string())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn handle(state: &AppState, text: &str) -> Value {
+    // Messages on an open socket don't pass through the HTTP middleware, so
+    // they have to count towards the idle timeout on their own.
+    let _guard = state.activity.begin();
+    let WsRequest { id, op } = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(err) => return json!({ "id": null, "error": format!("invalid request: {err}") }),
+    };
+    let result = match op {
+        WsOp::Get { key } => state.db_client.get_item(key).await.map(|item| json!(item)),
+        WsOp::GetAll => state
+            .db_client
+            .get_all_items()
+            .await
+            .map(|items| json!(items)),
+        WsOp::Put { key, value } => state
+            .db_client
+            .put_item(Item { key, value })
+            .await
+            .map(|()| Value::Null),
+    };
+    match result {
+        Ok(result) => json!({ "id": id, "result": result }),
+        Err(err) => json!({ "id": id, "error": state.error(err).message }),
+    }
+}